@@ -1,28 +1,55 @@
-use criterion::{Criterion, criterion_group, criterion_main};
+use criterion::{
+    BatchSize, BenchmarkGroup, BenchmarkId, Criterion, criterion_group, criterion_main,
+    measurement::WallTime,
+};
 use map::{open_addressing, swiss};
-use std::{collections::HashMap as StdHashMap, hint};
+use std::{collections::HashMap as StdHashMap, env, hash::Hash, hint};
 
-const BENCH_SIZE: usize = 1000;
+const DEFAULT_BENCH_SIZE: usize = 1000;
+
+/// Reads the number of entries per benchmark from `MAP_BENCH_SIZE`.
+fn bench_size() -> usize {
+    env::var("MAP_BENCH_SIZE")
+        .ok()
+        .and_then(|size| size.parse().ok())
+        .unwrap_or(DEFAULT_BENCH_SIZE)
+}
 
 fn generate_data(size: usize) -> Vec<(String, i32)> {
     (0..size).map(|i| (format!("key{}", i), i as i32)).collect()
 }
 
-fn bench_insert(c: &mut Criterion) {
-    let data = generate_data(BENCH_SIZE);
+fn generate_u64_data(size: usize) -> Vec<(u64, i32)> {
+    (0..size).map(|i| (i as u64, i as i32)).collect()
+}
 
+fn bench_insert(c: &mut Criterion) {
+    let size = bench_size();
     let mut group = c.benchmark_group("insert");
+    bench_insert_keys(&mut group, "string", &generate_data(size));
+    bench_insert_keys(&mut group, "u64", &generate_u64_data(size));
+    group.finish();
+}
 
-    group.bench_function("open_addressing", |b| {
-        b.iter(|| {
-            let mut map = open_addressing::Map::new();
-            for (key, value) in data.iter() {
-                map.insert(hint::black_box(key.clone()), hint::black_box(*value));
-            }
-        })
-    });
+fn bench_insert_keys<K: Hash + Eq + Clone>(
+    group: &mut BenchmarkGroup<'_, WallTime>,
+    key_type: &str,
+    data: &[(K, i32)],
+) {
+    group.bench_with_input(
+        BenchmarkId::new("open_addressing", key_type),
+        data,
+        |b, data| {
+            b.iter(|| {
+                let mut map = open_addressing::Map::new();
+                for (key, value) in data.iter() {
+                    map.insert(hint::black_box(key.clone()), hint::black_box(*value));
+                }
+            })
+        },
+    );
 
-    group.bench_function("swiss", |b| {
+    group.bench_with_input(BenchmarkId::new("swiss", key_type), data, |b, data| {
         b.iter(|| {
             let mut map = swiss::Map::new();
             for (key, value) in data.iter() {
@@ -31,20 +58,33 @@ fn bench_insert(c: &mut Criterion) {
         })
     });
 
-    group.bench_function("std_hashmap", |b| {
-        b.iter(|| {
-            let mut map = StdHashMap::new();
-            for (key, value) in data.iter() {
-                map.insert(hint::black_box(key.clone()), hint::black_box(*value));
-            }
-        })
-    });
+    group.bench_with_input(
+        BenchmarkId::new("std_hashmap", key_type),
+        data,
+        |b, data| {
+            b.iter(|| {
+                let mut map = StdHashMap::new();
+                for (key, value) in data.iter() {
+                    map.insert(hint::black_box(key.clone()), hint::black_box(*value));
+                }
+            })
+        },
+    );
+}
 
+fn bench_get(c: &mut Criterion) {
+    let size = bench_size();
+    let mut group = c.benchmark_group("get");
+    bench_get_keys(&mut group, "string", &generate_data(size));
+    bench_get_keys(&mut group, "u64", &generate_u64_data(size));
     group.finish();
 }
 
-fn bench_get(c: &mut Criterion) {
-    let data = generate_data(BENCH_SIZE);
+fn bench_get_keys<K: Hash + Eq + Clone>(
+    group: &mut BenchmarkGroup<'_, WallTime>,
+    key_type: &str,
+    data: &[(K, i32)],
+) {
     let mut oa_map = open_addressing::Map::new();
     let mut swiss_map = swiss::Map::new();
     let mut std_map = StdHashMap::new();
@@ -55,34 +95,272 @@ fn bench_get(c: &mut Criterion) {
         std_map.insert(key.clone(), *value);
     }
 
-    let mut group = c.benchmark_group("get");
+    group.bench_with_input(
+        BenchmarkId::new("open_addressing", key_type),
+        data,
+        |b, data| {
+            b.iter(|| {
+                for (key, _) in data.iter() {
+                    oa_map.get(hint::black_box(key));
+                }
+            })
+        },
+    );
 
-    group.bench_function("open_addressing", |b| {
+    group.bench_with_input(BenchmarkId::new("swiss", key_type), data, |b, data| {
         b.iter(|| {
             for (key, _) in data.iter() {
-                oa_map.get(hint::black_box(key));
+                swiss_map.get(hint::black_box(key));
             }
         })
     });
 
+    group.bench_with_input(
+        BenchmarkId::new("std_hashmap", key_type),
+        data,
+        |b, data| {
+            b.iter(|| {
+                for (key, _) in data.iter() {
+                    std_map.get(hint::black_box(key));
+                }
+            })
+        },
+    );
+}
+
+fn bench_get_missing(c: &mut Criterion) {
+    let size = bench_size();
+    let data = generate_u64_data(size);
+    let missing: Vec<u64> = (size as u64..2 * size as u64).collect();
+    let mut swiss_map = swiss::Map::new();
+    let mut std_map = StdHashMap::new();
+    for &(key, value) in data.iter() {
+        swiss_map.insert(key, value);
+        std_map.insert(key, value);
+    }
+
+    let mut group = c.benchmark_group("get_missing");
     group.bench_function("swiss", |b| {
         b.iter(|| {
-            for (key, _) in data.iter() {
+            for key in missing.iter() {
                 swiss_map.get(hint::black_box(key));
             }
         })
     });
-
     group.bench_function("std_hashmap", |b| {
         b.iter(|| {
-            for (key, _) in data.iter() {
+            for key in missing.iter() {
                 std_map.get(hint::black_box(key));
             }
         })
     });
+    group.finish();
+}
+
+/// Looks keys up in a table too large for cache and filled close to its load
+/// factor, where probe chains cross several groups and each new group is a
+/// likely cache miss.
+fn bench_get_high_load(c: &mut Criterion) {
+    const SLOT_COUNT: usize = 1 << 20;
+    const LOAD_FACTOR: f64 = 0.95;
+    let size = (SLOT_COUNT as f64 * (LOAD_FACTOR - 0.01)) as u64;
+    // A chunk size of one grows the table only when it must, so it ends up
+    // as full as the load factor allows.
+    let mut builder: swiss::MapBuilder<u64, u64> =
+        swiss::MapBuilder::with_chunk_size(1).with_load_factor(LOAD_FACTOR);
+    for key in 0..size {
+        builder.push(key, key);
+    }
+    let map = builder.finish();
+    let hits: Vec<u64> = (0..size).step_by(7).collect();
+    let missing: Vec<u64> = (size..size + hits.len() as u64).collect();
+
+    let mut group = c.benchmark_group("get_high_load");
+    group.bench_function("hit", |b| {
+        b.iter(|| {
+            for key in hits.iter() {
+                hint::black_box(map.get(hint::black_box(key)));
+            }
+        })
+    });
+    group.bench_function("miss", |b| {
+        b.iter(|| {
+            for key in missing.iter() {
+                hint::black_box(map.get(hint::black_box(key)));
+            }
+        })
+    });
+    group.finish();
+}
+
+fn bench_delete(c: &mut Criterion) {
+    let data = generate_u64_data(bench_size());
+    let mut group = c.benchmark_group("delete");
+
+    group.bench_function("open_addressing", |b| {
+        b.iter_batched(
+            || data.iter().copied().collect::<open_addressing::Map<_, _>>(),
+            |mut map| {
+                for (key, _) in data.iter() {
+                    map.delete(hint::black_box(key));
+                }
+                map
+            },
+            BatchSize::SmallInput,
+        )
+    });
+
+    group.bench_function("swiss", |b| {
+        b.iter_batched(
+            || data.iter().copied().collect::<swiss::Map<_, _>>(),
+            |mut map| {
+                for (key, _) in data.iter() {
+                    map.delete(hint::black_box(key));
+                }
+                map
+            },
+            BatchSize::SmallInput,
+        )
+    });
+
+    group.bench_function("std_hashmap", |b| {
+        b.iter_batched(
+            || data.iter().copied().collect::<StdHashMap<_, _>>(),
+            |mut map| {
+                for (key, _) in data.iter() {
+                    map.remove(hint::black_box(key));
+                }
+                map
+            },
+            BatchSize::SmallInput,
+        )
+    });
+
+    group.finish();
+}
+
+/// Slides a window of live keys along: each step inserts a new key, looks up
+/// one in the middle of the window and deletes the oldest, so a table that
+/// leaves tombstones behind keeps accumulating them.
+fn bench_mixed(c: &mut Criterion) {
+    let size = bench_size() as u64;
+    let mut group = c.benchmark_group("mixed");
+
+    group.bench_function("open_addressing", |b| {
+        b.iter_batched(
+            || {
+                (0..size)
+                    .map(|i| (i, i))
+                    .collect::<open_addressing::Map<_, _>>()
+            },
+            |mut map| {
+                for i in 0..size {
+                    map.insert(hint::black_box(size + i), i);
+                    hint::black_box(map.get(&(i + size / 2)));
+                    map.delete(hint::black_box(&i));
+                }
+                map
+            },
+            BatchSize::SmallInput,
+        )
+    });
+
+    group.bench_function("swiss", |b| {
+        b.iter_batched(
+            || (0..size).map(|i| (i, i)).collect::<swiss::Map<_, _>>(),
+            |mut map| {
+                for i in 0..size {
+                    map.insert(hint::black_box(size + i), i);
+                    hint::black_box(map.get(&(i + size / 2)));
+                    map.delete(hint::black_box(&i));
+                }
+                map
+            },
+            BatchSize::SmallInput,
+        )
+    });
+
+    group.bench_function("std_hashmap", |b| {
+        b.iter_batched(
+            || (0..size).map(|i| (i, i)).collect::<StdHashMap<_, _>>(),
+            |mut map| {
+                for i in 0..size {
+                    map.insert(hint::black_box(size + i), i);
+                    hint::black_box(map.get(&(i + size / 2)));
+                    map.remove(hint::black_box(&i));
+                }
+                map
+            },
+            BatchSize::SmallInput,
+        )
+    });
+
+    group.finish();
+}
+
+fn bench_iter(c: &mut Criterion) {
+    let data = generate_u64_data(bench_size());
+    let oa_map: open_addressing::Map<_, _> = data.iter().copied().collect();
+    let swiss_map: swiss::Map<_, _> = data.iter().copied().collect();
+    let std_map: StdHashMap<_, _> = data.iter().copied().collect();
+
+    let mut group = c.benchmark_group("iter");
+    group.bench_function("open_addressing", |b| {
+        b.iter(|| {
+            for entry in oa_map.iter() {
+                hint::black_box(entry);
+            }
+        })
+    });
+    group.bench_function("swiss", |b| {
+        b.iter(|| {
+            for entry in swiss_map.iter() {
+                hint::black_box(entry);
+            }
+        })
+    });
+    group.bench_function("std_hashmap", |b| {
+        b.iter(|| {
+            for entry in std_map.iter() {
+                hint::black_box(entry);
+            }
+        })
+    });
+    group.finish();
+}
+
+fn bench_build(c: &mut Criterion) {
+    const BUILD_SIZE: usize = 100_000;
+    let data = generate_data(BUILD_SIZE);
+
+    let mut group = c.benchmark_group("build");
+
+    group.bench_function("swiss_insert", |b| {
+        b.iter(|| {
+            let mut map = swiss::Map::new();
+            for (key, value) in data.iter() {
+                map.insert(key.clone(), *value);
+            }
+            hint::black_box(map)
+        })
+    });
+
+    group.bench_function("swiss_bucketed", |b| {
+        b.iter(|| hint::black_box(swiss::Map::build_bucketed(data.clone())))
+    });
 
     group.finish();
 }
 
-criterion_group!(benches, bench_insert, bench_get);
+criterion_group!(
+    benches,
+    bench_insert,
+    bench_get,
+    bench_get_missing,
+    bench_get_high_load,
+    bench_delete,
+    bench_mixed,
+    bench_iter,
+    bench_build
+);
 criterion_main!(benches);
@@ -0,0 +1,65 @@
+/// A HyperLogLog sketch estimating how many distinct hashes it has seen, to
+/// within about one percent, using a fixed 16 KiB of registers.
+pub struct CardinalityEstimator {
+    registers: Vec<u8>,
+}
+
+const PRECISION: u32 = 14;
+
+impl CardinalityEstimator {
+    pub fn new() -> Self {
+        Self {
+            registers: vec![0; 1 << PRECISION],
+        }
+    }
+
+    pub fn insert_hash(&mut self, hash: u64) {
+        let index = (hash >> (u64::BITS - PRECISION)) as usize;
+        let rank = (hash << PRECISION)
+            .leading_zeros()
+            .min(u64::BITS - PRECISION)
+            + 1;
+        self.registers[index] = self.registers[index].max(rank as u8);
+    }
+
+    pub fn estimate(&self) -> usize {
+        let m = self.registers.len() as f64;
+        let alpha = 0.7213 / (1.0 + 1.079 / m);
+        let sum: f64 = self.registers.iter().map(|&r| 2f64.powi(-(r as i32))).sum();
+        let raw = alpha * m * m / sum;
+        let zeros = self.registers.iter().filter(|&&r| r == 0).count();
+        // Small cardinalities leave registers untouched; linear counting is
+        // far more accurate there.
+        if raw <= 2.5 * m && zeros > 0 {
+            (m * (m / zeros as f64).ln()).round() as usize
+        } else {
+            raw.round() as usize
+        }
+    }
+}
+
+impl Default for CardinalityEstimator {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::hash::{BuildHasher, RandomState};
+
+    use super::*;
+
+    #[test]
+    fn test_estimate() {
+        let hasher = RandomState::new();
+        let mut estimator = CardinalityEstimator::new();
+        assert_eq!(estimator.estimate(), 0);
+
+        for i in 0..1000 {
+            estimator.insert_hash(hasher.hash_one(i));
+            estimator.insert_hash(hasher.hash_one(i));
+        }
+        assert!(estimator.estimate().abs_diff(1000) <= 50);
+    }
+}
@@ -0,0 +1,250 @@
+use std::{
+    hash::{BuildHasher, Hash, RandomState},
+    sync::{RwLock, RwLockWriteGuard},
+};
+
+use crate::swiss;
+
+/// A `swiss::Map` split into independently locked shards, so that threads
+/// working on unrelated keys rarely contend.
+pub struct ConcurrentMap<K: Hash + Eq, V> {
+    shards: Vec<RwLock<swiss::Map<K, V>>>,
+    hasher: RandomState,
+}
+
+impl<K: Hash + Eq, V> ConcurrentMap<K, V> {
+    pub fn new() -> Self {
+        const DEFAULT_SHARD_COUNT: usize = 16;
+        Self::with_shard_count(DEFAULT_SHARD_COUNT)
+    }
+
+    /// Creates a map with `shard_count` shards, which must be a power of two.
+    pub fn with_shard_count(shard_count: usize) -> Self {
+        assert!(
+            shard_count.is_power_of_two(),
+            "shard count must be a power of two"
+        );
+        Self {
+            shards: (0..shard_count)
+                .map(|_| RwLock::new(swiss::Map::new()))
+                .collect(),
+            hasher: RandomState::new(),
+        }
+    }
+
+    pub fn insert(&self, key: K, value: V) -> Option<V> {
+        self.shard(&key).write().unwrap().insert(key, value)
+    }
+
+    pub fn remove(&self, key: &K) -> Option<V> {
+        self.shard(key).write().unwrap().delete(key)
+    }
+
+    pub fn get_cloned(&self, key: &K) -> Option<V>
+    where
+        V: Clone,
+    {
+        self.get_with(key, V::clone)
+    }
+
+    /// Runs `f` on the value for `key` while its shard is read-locked.
+    pub fn get_with<R>(&self, key: &K, f: impl FnOnce(&V) -> R) -> Option<R> {
+        self.shard(key).read().unwrap().get(key).map(f)
+    }
+
+    /// Runs `f` on the entry for `key` while its shard is write-locked, making
+    /// read-modify-write sequences atomic, including inserting on a miss.
+    pub fn update<R>(&self, key: K, f: impl FnOnce(swiss::Entry<'_, K, V>) -> R) -> R {
+        f(self.shard(&key).write().unwrap().entry(key))
+    }
+
+    /// Clones every entry, locking one shard at a time. Each shard is copied
+    /// consistently, but writes to other shards may land mid-snapshot, so the
+    /// result is not a single point-in-time view of the whole map.
+    pub fn snapshot(&self) -> Vec<(K, V)>
+    where
+        K: Clone,
+        V: Clone,
+    {
+        let mut entries = Vec::new();
+        for shard in &self.shards {
+            let shard = shard.read().unwrap();
+            entries.extend(shard.iter().map(|(k, v)| (k.clone(), v.clone())));
+        }
+        entries
+    }
+
+    /// Write-locks every shard, in order, for the duration of `f`, so that
+    /// everything done through the `BulkView` appears atomic to other threads.
+    pub fn bulk<R>(&self, f: impl FnOnce(&mut BulkView<'_, K, V>) -> R) -> R {
+        let mut view = BulkView {
+            map: self,
+            shards: self.shards.iter().map(|s| s.write().unwrap()).collect(),
+        };
+        f(&mut view)
+    }
+
+    fn shard(&self, key: &K) -> &RwLock<swiss::Map<K, V>> {
+        &self.shards[self.shard_index(key)]
+    }
+
+    fn shard_index(&self, key: &K) -> usize {
+        let shard_bits = self.shards.len().trailing_zeros();
+        let h = self.hasher.hash_one(key);
+        h.checked_shr(u64::BITS - shard_bits).unwrap_or(0) as usize
+    }
+}
+
+impl<K: Hash + Eq, V> Default for ConcurrentMap<K, V> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Access to a `ConcurrentMap` with all of its shards locked, handed out by
+/// `ConcurrentMap::bulk`.
+pub struct BulkView<'a, K: Hash + Eq, V> {
+    map: &'a ConcurrentMap<K, V>,
+    shards: Vec<RwLockWriteGuard<'a, swiss::Map<K, V>>>,
+}
+
+impl<K: Hash + Eq, V> BulkView<'_, K, V> {
+    pub fn insert(&mut self, key: K, value: V) -> Option<V> {
+        let i = self.map.shard_index(&key);
+        self.shards[i].insert(key, value)
+    }
+
+    pub fn get(&self, key: &K) -> Option<&V> {
+        self.shards[self.map.shard_index(key)].get(key)
+    }
+
+    pub fn remove(&mut self, key: &K) -> Option<V> {
+        let i = self.map.shard_index(key);
+        self.shards[i].delete(key)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::thread;
+
+    use super::*;
+
+    #[test]
+    fn test_get_cloned_and_get_with() {
+        let map = ConcurrentMap::new();
+        let n = 1000;
+
+        thread::scope(|s| {
+            for t in 0..4 {
+                let map = &map;
+                s.spawn(move || {
+                    for i in (t..n).step_by(4) {
+                        map.insert(i, i.to_string());
+                    }
+                });
+            }
+            for _ in 0..4 {
+                let map = &map;
+                s.spawn(move || {
+                    for i in 0..n {
+                        if let Some(v) = map.get_cloned(&i) {
+                            assert_eq!(v, i.to_string());
+                        }
+                        if let Some(len) = map.get_with(&i, String::len) {
+                            assert_eq!(len, i.to_string().len());
+                        }
+                    }
+                });
+            }
+        });
+
+        for i in 0..n {
+            assert_eq!(map.get_cloned(&i), Some(i.to_string()));
+        }
+        assert_eq!(map.get_with(&n, String::len), None);
+    }
+
+    #[test]
+    fn test_update() {
+        let map = ConcurrentMap::new();
+
+        // Every thread may be the one to find the key missing and insert it.
+        thread::scope(|s| {
+            for _ in 0..8 {
+                s.spawn(|| {
+                    for _ in 0..1000 {
+                        map.update("counter", |entry| *entry.or_insert(0) += 1);
+                    }
+                });
+            }
+        });
+
+        assert_eq!(map.get_cloned(&"counter"), Some(8000));
+        assert!(map.update("missing", |entry| matches!(entry, swiss::Entry::Vacant(_))));
+        assert_eq!(map.get_cloned(&"missing"), None);
+    }
+
+    #[test]
+    fn test_bulk() {
+        let map = ConcurrentMap::new();
+        map.insert("a", 1);
+        map.insert("b", 2);
+
+        thread::scope(|s| {
+            s.spawn(|| {
+                for _ in 0..1000 {
+                    map.bulk(|view| {
+                        let a = view.remove(&"a").unwrap();
+                        let b = view.remove(&"b").unwrap();
+                        view.insert("a", b);
+                        view.insert("b", a);
+                    });
+                }
+            });
+            s.spawn(|| {
+                for _ in 0..1000 {
+                    let (a, b) =
+                        map.bulk(|view| (view.get(&"a").copied(), view.get(&"b").copied()));
+                    assert!(matches!((a, b), (Some(1), Some(2)) | (Some(2), Some(1))));
+                }
+            });
+        });
+
+        assert_eq!(map.get_cloned(&"a"), Some(1));
+        assert_eq!(map.get_cloned(&"b"), Some(2));
+    }
+
+    #[test]
+    fn test_snapshot() {
+        let map = ConcurrentMap::new();
+        let n = 1000;
+
+        thread::scope(|s| {
+            for t in 0..4 {
+                let map = &map;
+                s.spawn(move || {
+                    for round in 0..5 {
+                        for i in (t..n).step_by(4) {
+                            map.insert(i, (i * round, round));
+                        }
+                    }
+                });
+            }
+            s.spawn(|| {
+                for _ in 0..20 {
+                    for (k, (product, round)) in map.snapshot() {
+                        assert_eq!(product, k * round);
+                    }
+                }
+            });
+        });
+
+        let mut snapshot = map.snapshot();
+        snapshot.sort();
+        assert_eq!(
+            snapshot,
+            (0..n).map(|i| (i, (i * 4, 4))).collect::<Vec<_>>()
+        );
+    }
+}
@@ -0,0 +1,232 @@
+//! Replays random operation sequences against both hand-rolled maps and
+//! `std::collections::HashMap`, comparing every return value and the full
+//! contents after every step. A failing sequence is shrunk to a minimal one
+//! before it is reported.
+
+use std::{
+    collections::HashMap as StdHashMap,
+    hash::{BuildHasher, BuildHasherDefault, DefaultHasher, Hasher},
+};
+
+use crate::{open_addressing, swiss};
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+enum Op {
+    Insert(u16, u32),
+    Get(u16),
+    Delete(u16),
+    Contains(u16),
+}
+
+#[derive(Debug, PartialEq)]
+enum Outcome {
+    Value(Option<u32>),
+    Found(bool),
+}
+
+trait TestMap {
+    fn apply(&mut self, op: Op) -> Outcome;
+    fn entries(&self) -> Vec<(u16, u32)>;
+}
+
+impl<S: BuildHasher> TestMap for open_addressing::Map<u16, u32, S> {
+    fn apply(&mut self, op: Op) -> Outcome {
+        match op {
+            Op::Insert(k, v) => Outcome::Value(self.insert(k, v)),
+            Op::Get(k) => Outcome::Value(self.get(&k).copied()),
+            Op::Delete(k) => Outcome::Value(self.delete(&k)),
+            Op::Contains(k) => Outcome::Found(self.contains_key(&k)),
+        }
+    }
+
+    fn entries(&self) -> Vec<(u16, u32)> {
+        sorted(self.iter().map(|(&k, &v)| (k, v)))
+    }
+}
+
+impl<S: BuildHasher, const G: usize> TestMap for swiss::Map<u16, u32, S, G> {
+    fn apply(&mut self, op: Op) -> Outcome {
+        match op {
+            Op::Insert(k, v) => Outcome::Value(self.insert(k, v)),
+            Op::Get(k) => Outcome::Value(self.get(&k).copied()),
+            Op::Delete(k) => Outcome::Value(self.delete(&k)),
+            Op::Contains(k) => Outcome::Found(self.contains_key(&k)),
+        }
+    }
+
+    fn entries(&self) -> Vec<(u16, u32)> {
+        sorted(self.iter().map(|(&k, &v)| (k, v)))
+    }
+}
+
+impl TestMap for StdHashMap<u16, u32> {
+    fn apply(&mut self, op: Op) -> Outcome {
+        match op {
+            Op::Insert(k, v) => Outcome::Value(self.insert(k, v)),
+            Op::Get(k) => Outcome::Value(self.get(&k).copied()),
+            Op::Delete(k) => Outcome::Value(self.remove(&k)),
+            Op::Contains(k) => Outcome::Found(self.contains_key(&k)),
+        }
+    }
+
+    fn entries(&self) -> Vec<(u16, u32)> {
+        sorted(self.iter().map(|(&k, &v)| (k, v)))
+    }
+}
+
+fn sorted(entries: impl Iterator<Item = (u16, u32)>) -> Vec<(u16, u32)> {
+    let mut entries: Vec<_> = entries.collect();
+    entries.sort();
+    entries
+}
+
+/// Squeezes every key into a handful of home slots and tags, so that probe
+/// chains run long and wrap around.
+#[derive(Default)]
+struct ClusteredHasher(u64);
+
+impl Hasher for ClusteredHasher {
+    fn finish(&self) -> u64 {
+        ((self.0 % 4) << 7) | (self.0 % 3)
+    }
+
+    fn write(&mut self, bytes: &[u8]) {
+        for &byte in bytes {
+            self.0 = self.0 << 8 | byte as u64;
+        }
+    }
+}
+
+type Fixed = BuildHasherDefault<DefaultHasher>;
+type Clustered = BuildHasherDefault<ClusteredHasher>;
+
+/// Runs `ops` against a fresh `M` and std, returning the first divergence.
+fn check<M: TestMap + Default>(ops: &[Op]) -> Result<(), String> {
+    let mut map = M::default();
+    let mut reference = StdHashMap::new();
+    for (step, &op) in ops.iter().enumerate() {
+        let (got, expected) = (map.apply(op), reference.apply(op));
+        if got != expected {
+            return Err(format!(
+                "step {step}: {op:?} returned {got:?}, std returned {expected:?}"
+            ));
+        }
+        if map.entries() != reference.entries() {
+            return Err(format!("step {step}: contents diverged after {op:?}"));
+        }
+    }
+    Ok(())
+}
+
+/// Greedily drops chunks of operations, then lowers keys, for as long as the
+/// sequence keeps failing.
+fn shrink(mut ops: Vec<Op>, fails: impl Fn(&[Op]) -> bool) -> Vec<Op> {
+    let mut chunk = ops.len() / 2;
+    while chunk > 0 {
+        let mut start = 0;
+        while start < ops.len() {
+            let end = (start + chunk).min(ops.len());
+            let candidate: Vec<_> = [&ops[..start], &ops[end..]].concat();
+            if fails(&candidate) {
+                ops = candidate;
+            } else {
+                start += chunk;
+            }
+        }
+        chunk /= 2;
+    }
+    for i in 0..ops.len() {
+        loop {
+            let smaller = match ops[i] {
+                Op::Insert(k, v) if k > 0 => Op::Insert(k / 2, v),
+                Op::Get(k) if k > 0 => Op::Get(k / 2),
+                Op::Delete(k) if k > 0 => Op::Delete(k / 2),
+                Op::Contains(k) if k > 0 => Op::Contains(k / 2),
+                _ => break,
+            };
+            let mut candidate = ops.clone();
+            candidate[i] = smaller;
+            if !fails(&candidate) {
+                break;
+            }
+            ops = candidate;
+        }
+    }
+    ops
+}
+
+struct XorShift(u64);
+
+impl XorShift {
+    fn next(&mut self) -> u64 {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 7;
+        self.0 ^= self.0 << 17;
+        self.0
+    }
+
+    fn below(&mut self, n: u64) -> u64 {
+        self.next() % n
+    }
+}
+
+/// Generates a sequence over `key_count` keys, weighted towards inserts so
+/// that tables fill up and grow between the deletes.
+fn generate(rng: &mut XorShift, len: usize, key_count: u16) -> Vec<Op> {
+    (0..len)
+        .map(|_| {
+            let key = rng.below(key_count as u64) as u16;
+            match rng.below(10) {
+                0..=3 => Op::Insert(key, rng.next() as u32),
+                4..=5 => Op::Get(key),
+                6..=8 => Op::Delete(key),
+                _ => Op::Contains(key),
+            }
+        })
+        .collect()
+}
+
+fn run<M: TestMap + Default>(name: &str, seed: u64) {
+    let mut rng = XorShift(seed);
+    for case in 0..100 {
+        let key_count = [4, 16, 64, 512][case % 4];
+        let len = 1 + rng.below(400) as usize;
+        let ops = generate(&mut rng, len, key_count);
+        if check::<M>(&ops).is_err() {
+            let minimal = shrink(ops, |ops| check::<M>(ops).is_err());
+            let error = check::<M>(&minimal).unwrap_err();
+            panic!("{name} diverged from std: {error}\nminimal ops: {minimal:?}");
+        }
+    }
+}
+
+#[test]
+fn test_open_addressing_against_std() {
+    run::<open_addressing::Map<u16, u32, Fixed>>("open_addressing", 0x9e37_79b9_7f4a_7c15);
+    run::<open_addressing::Map<u16, u32, Clustered>>("open_addressing (clustered)", 0x2545_f491);
+}
+
+#[test]
+fn test_swiss_against_std() {
+    run::<swiss::Map<u16, u32, Fixed>>("swiss", 0x9e37_79b9_7f4a_7c15);
+    run::<swiss::Map<u16, u32, Clustered>>("swiss (clustered)", 0x2545_f491);
+    run::<swiss::Map<u16, u32, Fixed, 8>>("swiss (8-wide)", 0x9e37_79b9_7f4a_7c15);
+    run::<swiss::Map<u16, u32, Clustered, 8>>("swiss (8-wide, clustered)", 0x2545_f491);
+}
+
+#[test]
+fn test_shrink_finds_minimal_sequence() {
+    // Pretend a map loses key 3 once it has been deleted and reinserted.
+    let fails = |ops: &[Op]| {
+        let deleted = ops.iter().position(|&op| op == Op::Delete(3));
+        deleted.is_some_and(|i| ops[i..].iter().any(|op| matches!(op, Op::Insert(3, _))))
+    };
+    let mut rng = XorShift(7);
+    let mut ops = generate(&mut rng, 200, 8);
+    ops.splice(50..50, [Op::Delete(3), Op::Insert(3, 1)]);
+    assert!(fails(&ops));
+    let minimal = shrink(ops, fails);
+    assert_eq!(minimal.len(), 2);
+    assert_eq!(minimal[0], Op::Delete(3));
+    assert!(matches!(minimal[1], Op::Insert(3, _)));
+}
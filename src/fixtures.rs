@@ -0,0 +1,21 @@
+//! Test keys shared by the map modules.
+
+use std::{
+    cell::Cell,
+    hash::{Hash, Hasher},
+};
+
+/// Counts how many times it is hashed into a shared cell, to check that a
+/// map reuses stored hashes instead of rehashing its keys.
+#[derive(PartialEq, Eq)]
+pub struct HashCounter<'a> {
+    pub id: i32,
+    pub hashes: &'a Cell<usize>,
+}
+
+impl Hash for HashCounter<'_> {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.hashes.set(self.hashes.get() + 1);
+        self.id.hash(state);
+    }
+}
@@ -1,2 +1,23 @@
+pub mod cardinality;
+pub mod concurrent;
+#[cfg(test)]
+mod differential;
+#[cfg(test)]
+mod fixtures;
 pub mod open_addressing;
+pub mod robin_hood;
 pub mod swiss;
+
+/// Caps the preallocation a deserializer takes from an untrusted length hint.
+#[cfg(feature = "serde")]
+const MAX_PREALLOCATED: usize = 4096;
+
+/// Builds a `swiss::Map` from `key => value` pairs. The pairs go through an
+/// array, so the table is sized for all of them up front, and a later pair
+/// overwrites an earlier one with the same key.
+#[macro_export]
+macro_rules! map {
+    ($($key:expr => $value:expr),* $(,)?) => {
+        $crate::swiss::Map::from([$(($key, $value)),*])
+    };
+}
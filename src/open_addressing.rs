@@ -1,23 +1,46 @@
 use std::{
+    borrow::Borrow,
+    error::Error,
+    fmt::{self, Debug},
     hash::{BuildHasher, Hash, RandomState},
     iter, mem,
+    ops::Index,
 };
 
-pub struct Map<K: Hash + Eq, V> {
-    slots: Vec<Slot<Entry<K, V>>>,
+/// Cloning copies the table slot for slot. The hasher is cloned along with
+/// it, keeping its keys, so nothing needs to be rehashed.
+#[derive(Clone)]
+pub struct Map<K: Hash + Eq, V, S = RandomState> {
+    slots: Vec<Slot<Bucket<K, V>>>,
     count: usize,
-    hasher: RandomState,
+    hasher: S,
+    max_load_factor: f64,
+    #[cfg(any(test, feature = "debug-internals"))]
+    resize_count: usize,
 }
 
+/// Probe distances of the occupied slots, as returned by `Map::probe_stats`.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct ProbeStats {
+    pub average: f64,
+    pub max: usize,
+    /// The distance that 99% of the entries are within.
+    pub p99: usize,
+    pub load_factor: f64,
+}
+
+#[derive(Clone)]
 enum Slot<T> {
     Empty,
-    Deleted,
     Occupied(T),
 }
 
-struct Entry<K, V> {
+#[derive(Clone)]
+struct Bucket<K, V> {
     key: K,
     value: V,
+    /// The full hash of `key`, kept so that resizing need not rehash it.
+    hash: u64,
 }
 
 const INITIAL_SIZE: usize = 64;
@@ -26,84 +49,432 @@ const EXPANSION_FACTOR: usize = 2;
 
 impl<K: Hash + Eq, V> Map<K, V> {
     pub fn new() -> Self {
+        Self::with_hasher(RandomState::new())
+    }
+
+    /// Creates a map that holds at least `cap` entries before expanding.
+    pub fn with_capacity(cap: usize) -> Self {
+        Self::with_slot_count(slot_count_for(cap, LOAD_FACTOR), RandomState::new())
+    }
+}
+
+impl<K: Hash + Eq, V, S: BuildHasher> Map<K, V, S> {
+    /// Creates a map that hashes its keys with `hasher`.
+    pub fn with_hasher(hasher: S) -> Self {
+        Self::with_slot_count(INITIAL_SIZE, hasher)
+    }
+
+    /// Creates a map that holds at least `cap` entries before expanding and
+    /// hashes its keys with `hasher`.
+    pub fn with_capacity_and_hasher(cap: usize, hasher: S) -> Self {
+        Self::with_slot_count(slot_count_for(cap, LOAD_FACTOR), hasher)
+    }
+
+    fn with_slot_count(slot_count: usize, hasher: S) -> Self {
         Map {
-            slots: iter::repeat_with(|| Slot::Empty)
-                .take(INITIAL_SIZE)
-                .collect(),
+            slots: iter::repeat_with(|| Slot::Empty).take(slot_count).collect(),
             count: 0,
-            hasher: RandomState::new(),
+            hasher,
+            max_load_factor: LOAD_FACTOR,
+            #[cfg(any(test, feature = "debug-internals"))]
+            resize_count: 0,
         }
     }
 
     pub fn insert(&mut self, key: K, value: V) -> Option<V> {
-        match self.find_index(&key) {
+        let hash = self.hasher.hash_one(&key);
+        match self.find_index_hashed(&key, hash) {
             Some(i) => Some(mem::replace(
                 &mut self.slots[i].as_mut().unwrap().value,
                 value,
             )),
             None => {
                 self.expand();
-                let i = self.find_empty(&key);
-                self.slots[i] = Slot::Occupied(Entry { key, value });
-                self.count += 1;
+                let i = self.find_empty(hash);
+                self.occupy(i, Bucket { key, value, hash });
                 None
             }
         }
     }
 
-    pub fn get(&self, key: &K) -> Option<&V> {
+    /// Inserts `value` only if `key` is absent. Otherwise the map is left
+    /// as is and the error hands back `value` along with the existing entry.
+    pub fn try_insert(&mut self, key: K, value: V) -> Result<&mut V, OccupiedError<'_, K, V, S>> {
+        match self.entry(key) {
+            Entry::Occupied(entry) => Err(OccupiedError { entry, value }),
+            Entry::Vacant(entry) => Ok(entry.insert(value)),
+        }
+    }
+
+    /// Inserts like `insert` and returns the stored value, so it can be
+    /// changed without looking the key up again.
+    pub fn insert_and_get(&mut self, key: K, value: V) -> &mut V {
+        match self.entry(key) {
+            Entry::Occupied(mut entry) => {
+                entry.insert(value);
+                entry.into_mut()
+            }
+            Entry::Vacant(entry) => entry.insert(value),
+        }
+    }
+
+    pub fn entry(&mut self, key: K) -> Entry<'_, K, V, S> {
+        let hash = self.hasher.hash_one(&key);
+        match self.find_index_hashed(&key, hash) {
+            Some(index) => Entry::Occupied(OccupiedEntry { map: self, index }),
+            None => {
+                // 先扩容再定位空槽，保证记下的下标在插入时仍然有效
+                self.expand();
+                let index = self.find_empty(hash);
+                Entry::Vacant(VacantEntry {
+                    map: self,
+                    key,
+                    hash,
+                    index,
+                })
+            }
+        }
+    }
+
+    /// Returns the value for `key`, inserting `f()` first if it is missing.
+    /// The key is hashed and looked up once either way.
+    pub fn get_or_insert_with<F: FnOnce() -> V>(&mut self, key: K, f: F) -> &mut V {
+        self.entry(key).or_insert_with(f)
+    }
+
+    pub fn get<Q>(&self, key: &Q) -> Option<&V>
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+    {
         let i = self.find_index(key)?;
         Some(&self.slots[i].as_ref().unwrap().value)
     }
 
-    pub fn get_mut(&mut self, key: &K) -> Option<&mut V> {
+    /// Returns the stored key along with its value.
+    pub fn get_key_value<Q>(&self, key: &Q) -> Option<(&K, &V)>
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+    {
+        let i = self.find_index(key)?;
+        let entry = self.slots[i].as_ref().unwrap();
+        Some((&entry.key, &entry.value))
+    }
+
+    pub fn get_mut<Q>(&mut self, key: &Q) -> Option<&mut V>
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+    {
         let i = self.find_index(key)?;
         Some(&mut self.slots[i].as_mut().unwrap().value)
     }
 
-    pub fn contains(&self, key: &K) -> bool {
+    /// Returns mutable references to the values of several keys at once, or
+    /// `None` if any key is missing or two of them are equal.
+    pub fn get_many_mut<Q, const N: usize>(&mut self, keys: [&Q; N]) -> Option<[&mut V; N]>
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+    {
+        let mut indices = [0; N];
+        for (n, key) in keys.into_iter().enumerate() {
+            let i = self.find_index(key)?;
+            // 相等的键落在同一个槽位，比较下标就能查出重复
+            if indices[..n].contains(&i) {
+                return None;
+            }
+            indices[n] = i;
+        }
+        let slots = self.slots.as_mut_ptr();
+        // 下标互不相同，得到的引用不会重叠
+        Some(indices.map(|i| {
+            // SAFETY: 下标都来自 find_index，在 slots 范围内且槽位已占用；
+            // 下标两两不同，这些引用又都借用自 &mut self，不会与其他访问重叠
+            unsafe { &mut (*slots.add(i)).as_mut().unwrap().value }
+        }))
+    }
+
+    pub fn contains_key<Q>(&self, key: &Q) -> bool
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+    {
         self.find_index(key).is_some()
     }
 
-    pub fn delete(&mut self, key: &K) -> Option<V> {
+    pub fn contains<Q>(&self, key: &Q) -> bool
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+    {
+        self.contains_key(key)
+    }
+
+    pub fn delete<Q>(&mut self, key: &Q) -> Option<V>
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+    {
+        self.remove_entry(key).map(|(_, value)| value)
+    }
+
+    /// Like `delete`, but also hands back the stored key.
+    pub fn remove_entry<Q>(&mut self, key: &Q) -> Option<(K, V)>
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+    {
         let i = self.find_index(key)?;
-        self.count -= 1;
-        Some(self.slots[i].delete().value)
+        let entry = self.remove_at(i);
+        Some((entry.key, entry.value))
+    }
+
+    /// Keeps only the entries for which `f` returns true.
+    pub fn retain<F: FnMut(&K, &mut V) -> bool>(&mut self, mut f: F) {
+        // 从空槽之后开始扫描：回移只发生在同一段连续探查链内，不会越过空槽，
+        // 所以被移回来的元素都还没有访问过，也不会被访问两次
+        let start = self
+            .slots
+            .iter()
+            .position(|slot| !slot.is_occupied())
+            .unwrap();
+        let len = self.slots.len();
+        let mut visited = 1;
+        let mut i = (start + 1) & (len - 1);
+        while visited < len {
+            if let Slot::Occupied(entry) = &mut self.slots[i]
+                && !f(&entry.key, &mut entry.value)
+            {
+                // 后面的元素可能回移到 i，需要原地再看一次
+                self.remove_at(i);
+                continue;
+            }
+            visited += 1;
+            i = (i + 1) & (len - 1);
+        }
+    }
+
+    /// Applies `f` to every entry in one pass over the slots.
+    pub fn update_all<F: FnMut(&K, &mut V)>(&mut self, mut f: F) {
+        for slot in self.slots.iter_mut() {
+            if let Slot::Occupied(entry) = slot {
+                f(&entry.key, &mut entry.value);
+            }
+        }
+    }
+
+    /// Removes all entries, keeping the allocated slots.
+    pub fn clear(&mut self) {
+        self.slots.fill_with(|| Slot::Empty);
+        self.count = 0;
+    }
+
+    /// Moves every entry out of `other` into this map, leaving `other` empty
+    /// with its slots still allocated. Values from `other` win where both
+    /// maps hold a key.
+    pub fn append(&mut self, other: &mut Self) {
+        self.reserve(other.count);
+        for slot in other.slots.iter_mut() {
+            if let Slot::Occupied(entry) = mem::replace(slot, Slot::Empty) {
+                other.count -= 1;
+                self.insert(entry.key, entry.value);
+            }
+        }
+    }
+
+    /// Returns the fraction of slots holding live entries.
+    pub fn load_factor(&self) -> f64 {
+        self.count as f64 / self.slots.len() as f64
+    }
+
+    /// Returns how many entries the map can hold before it next expands.
+    pub fn capacity(&self) -> usize {
+        (self.slots.len() as f64 * self.max_load_factor) as usize
+    }
+
+    /// Grows the table so that `additional` more entries fit without
+    /// expanding. Does nothing if there is already room.
+    pub fn reserve(&mut self, additional: usize) {
+        let slot_count = slot_count_for(self.count + additional, self.max_load_factor);
+        if slot_count > self.slots.len() {
+            self.resize(slot_count);
+        }
+    }
+
+    /// Rebuilds the table at the smallest size that holds the live entries.
+    pub fn shrink_to_fit(&mut self) {
+        self.resize(slot_count_for(self.count, self.max_load_factor));
+    }
+
+    pub fn hasher(&self) -> &S {
+        &self.hasher
+    }
+
+    pub fn len(&self) -> usize {
+        self.count
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.count == 0
     }
 
     pub fn iter(&self) -> Iter<'_, K, V> {
-        Iter { map: self, i: 0 }
+        Iter {
+            slots: &self.slots,
+            i: 0,
+        }
     }
 
     pub fn iter_mut(&mut self) -> IterMut<'_, K, V> {
-        IterMut { map: self, i: 0 }
+        IterMut {
+            slots: &mut self.slots,
+            i: 0,
+        }
     }
 
-    fn find_index(&self, key: &K) -> Option<usize> {
+    /// Returns how many times the table has been expanded.
+    #[cfg(any(test, feature = "debug-internals"))]
+    pub fn resize_count(&self) -> usize {
+        self.resize_count
+    }
+
+    /// Removes every entry in the probe chain of `key`, from its home slot up
+    /// to the next empty slot, and returns them in slot order. Returns nothing
+    /// if `key` is absent.
+    pub fn remove_chain(&mut self, key: &K) -> Vec<(K, V)> {
+        self.remove_chain_if(key, |_, _| true)
+    }
+
+    /// Like `remove_chain`, but only returns the entries for which `f` returns
+    /// true and puts the rest back into the map.
+    pub fn remove_chain_if<F: FnMut(&K, &V) -> bool>(&mut self, key: &K, mut f: F) -> Vec<(K, V)> {
+        if self.find_index(key).is_none() {
+            return Vec::new();
+        }
+        // 链中的槽位全部置空是安全的：其他元素的探查路径都不会经过这一段
         let mut i = self.hash(key);
         let start_index = i;
+        let mut chain = Vec::new();
+        loop {
+            match mem::replace(&mut self.slots[i], Slot::Empty) {
+                Slot::Empty => break,
+                Slot::Occupied(entry) => chain.push(entry),
+            }
+            i = (i + 1) & (self.slots.len() - 1);
+            if i == start_index {
+                break;
+            }
+        }
+        self.count -= chain.len();
+        let mut removed = Vec::new();
+        for entry in chain {
+            if f(&entry.key, &entry.value) {
+                removed.push((entry.key, entry.value));
+            } else {
+                let i = self.find_empty(entry.hash);
+                self.occupy(i, entry);
+            }
+        }
+        removed
+    }
+
+    /// Returns roughly how many heap bytes the slots take. Memory owned by
+    /// the keys and values themselves is not counted.
+    pub fn memory_footprint(&self) -> usize {
+        self.slots.len() * mem::size_of::<Slot<Bucket<K, V>>>()
+    }
+
+    /// Returns the bytes allocated for the table per byte of live key-value
+    /// data, or infinity for an empty map.
+    pub fn overhead_ratio(&self) -> f64 {
+        self.memory_footprint() as f64 / (self.count * mem::size_of::<(K, V)>()) as f64
+    }
+
+    /// Summarizes how far entries sit from their home slots, to help spot
+    /// keys that cluster badly. Deletion shifts entries back instead of
+    /// leaving tombstones, so there are none to report.
+    pub fn probe_stats(&self) -> ProbeStats {
+        let histogram = self.probe_histogram();
+        let total: usize = histogram.iter().sum();
+        let sum: usize = histogram.iter().enumerate().map(|(d, n)| d * n).sum();
+        let p99_rank = (total * 99).div_ceil(100);
+        let mut seen = 0;
+        let p99 = histogram
+            .iter()
+            .position(|n| {
+                seen += n;
+                seen >= p99_rank
+            })
+            .unwrap_or(0);
+        ProbeStats {
+            average: if total == 0 {
+                0.0
+            } else {
+                sum as f64 / total as f64
+            },
+            max: histogram.len().saturating_sub(1),
+            p99,
+            load_factor: self.load_factor(),
+        }
+    }
+
+    /// Returns how many entries sit at each probe distance from their home
+    /// slot, indexed by distance.
+    pub fn probe_histogram(&self) -> Vec<usize> {
+        let mut histogram = Vec::new();
+        for (i, slot) in self.slots.iter().enumerate() {
+            if let Slot::Occupied(entry) = slot {
+                let distance =
+                    (i + self.slots.len() - self.home(entry.hash)) & (self.slots.len() - 1);
+                if histogram.len() <= distance {
+                    histogram.resize(distance + 1, 0);
+                }
+                histogram[distance] += 1;
+            }
+        }
+        histogram
+    }
+
+    fn find_index<Q>(&self, key: &Q) -> Option<usize>
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+    {
+        self.find_index_hashed(key, self.hasher.hash_one(key))
+    }
+
+    fn find_index_hashed<Q>(&self, key: &Q, hash: u64) -> Option<usize>
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+    {
+        let mut i = self.home(hash);
+        let start_index = i;
         loop {
             match &self.slots[i] {
                 Slot::Empty => return None,
-                Slot::Occupied(entry) if entry.key == *key => return Some(i),
+                Slot::Occupied(entry) if entry.hash == hash && entry.key.borrow() == key => {
+                    return Some(i);
+                }
                 _ => {}
             }
-            i = (i + 1) % self.slots.len();
+            i = (i + 1) & (self.slots.len() - 1);
             if i == start_index {
                 return None;
             }
         }
     }
 
-    fn find_empty(&self, key: &K) -> usize {
-        let mut i = self.hash(key);
+    fn find_empty(&self, hash: u64) -> usize {
+        let mut i = self.home(hash);
         let start_index = i;
         loop {
-            match self.slots[i] {
-                Slot::Empty | Slot::Deleted => return i,
-                _ => {}
+            if !self.slots[i].is_occupied() {
+                return i;
             }
-            i = (i + 1) % self.slots.len();
+            i = (i + 1) & (self.slots.len() - 1);
             if i == start_index {
                 unreachable!("The map should always has empty slots.")
             }
@@ -111,26 +482,82 @@ impl<K: Hash + Eq, V> Map<K, V> {
     }
 
     fn expand(&mut self) {
-        if ((self.count as f64) / (self.slots.len() as f64)) < LOAD_FACTOR {
+        // 负载因子接近 1 时，小表可能在达到阈值之前就被填满，
+        // 所以还要保证插入之后至少留下一个空槽
+        if ((self.count as f64) / (self.slots.len() as f64)) < self.max_load_factor
+            && self.count + 1 < self.slots.len()
+        {
             return;
         }
-        let new_slots = iter::repeat_with(|| Slot::Empty)
-            .take(self.slots.len() * EXPANSION_FACTOR)
-            .collect();
+        self.resize(self.slots.len() * EXPANSION_FACTOR);
+    }
+
+    fn resize(&mut self, slot_count: usize) {
+        let new_slots = iter::repeat_with(|| Slot::Empty).take(slot_count).collect();
         let old_slots = mem::replace(&mut self.slots, new_slots);
         self.count = 0;
+        #[cfg(any(test, feature = "debug-internals"))]
+        {
+            self.resize_count += 1;
+        }
         old_slots
             .into_iter()
             .filter(|slot| slot.is_occupied())
             .map(|slot| slot.unwrap())
             .for_each(|entry| {
-                self.insert(entry.key, entry.value);
+                let i = self.find_empty(entry.hash);
+                self.occupy(i, entry);
             });
     }
 
-    fn hash(&self, key: &K) -> usize {
-        self.hasher.hash_one(key) as usize % self.slots.len()
+    fn occupy(&mut self, i: usize, entry: Bucket<K, V>) {
+        self.slots[i] = Slot::Occupied(entry);
+        self.count += 1;
+    }
+
+    /// Removes the entry at `i` and closes the gap by moving later entries of
+    /// the same probe run back, so no tombstone is left behind.
+    fn remove_at(&mut self, i: usize) -> Bucket<K, V> {
+        let entry = mem::replace(&mut self.slots[i], Slot::Empty).unwrap();
+        self.count -= 1;
+        let len = self.slots.len();
+        let mut gap = i;
+        let mut j = (i + 1) & (len - 1);
+        while let Slot::Occupied(next) = &self.slots[j] {
+            // 只有当元素的理想位置不在 (gap, j] 之间时，才能回移到 gap
+            let home = self.home(next.hash);
+            if (j + len - home) & (len - 1) >= (j + len - gap) & (len - 1) {
+                self.slots.swap(gap, j);
+                gap = j;
+            }
+            j = (j + 1) & (len - 1);
+        }
+        entry
+    }
+
+    fn hash<Q>(&self, key: &Q) -> usize
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+    {
+        self.home(self.hasher.hash_one(key))
+    }
+
+    /// Returns the home slot of an entry with the given hash.
+    fn home(&self, hash: u64) -> usize {
+        debug_assert!(self.slots.len().is_power_of_two());
+        hash as usize & (self.slots.len() - 1)
+    }
+}
+
+/// Returns the smallest power-of-two slot count, no smaller than
+/// `INITIAL_SIZE`, that holds `cap` entries under `load_factor`.
+fn slot_count_for(cap: usize, load_factor: f64) -> usize {
+    let mut slot_count = INITIAL_SIZE;
+    while ((slot_count as f64 * load_factor) as usize) < cap {
+        slot_count *= EXPANSION_FACTOR;
     }
+    slot_count
 }
 
 impl<T> Slot<T> {
@@ -145,7 +572,6 @@ impl<T> Slot<T> {
     fn as_ref(&self) -> Slot<&T> {
         match self {
             Self::Empty => Slot::Empty,
-            Self::Deleted => Slot::Deleted,
             Self::Occupied(value) => Slot::Occupied(value),
         }
     }
@@ -153,22 +579,340 @@ impl<T> Slot<T> {
     fn as_mut(&mut self) -> Slot<&mut T> {
         match self {
             Self::Empty => Slot::Empty,
-            Self::Deleted => Slot::Deleted,
             Self::Occupied(value) => Slot::Occupied(value),
         }
     }
 
-    fn delete(&mut self) -> T {
-        mem::replace(self, Self::Deleted).unwrap()
-    }
-
     fn is_occupied(&self) -> bool {
         matches!(self, Self::Occupied(_))
     }
 }
 
+impl<K: Hash + Eq, V, S: BuildHasher + Default> Default for Map<K, V, S> {
+    fn default() -> Self {
+        Self::with_hasher(S::default())
+    }
+}
+
+impl<K: Hash + Eq, V: PartialEq, S: BuildHasher> PartialEq for Map<K, V, S> {
+    fn eq(&self, other: &Self) -> bool {
+        self.count == other.count && self.iter().all(|(k, v)| other.get(k) == Some(v))
+    }
+}
+
+impl<K: Hash + Eq, V: Eq, S: BuildHasher> Eq for Map<K, V, S> {}
+
+impl<K: Hash + Eq, V, S: BuildHasher + Default> FromIterator<(K, V)> for Map<K, V, S> {
+    fn from_iter<I: IntoIterator<Item = (K, V)>>(iter: I) -> Self {
+        let iter = iter.into_iter();
+        let mut map = Self::with_slot_count(
+            slot_count_for(iter.size_hint().0, LOAD_FACTOR),
+            S::default(),
+        );
+        for (key, value) in iter {
+            map.insert(key, value);
+        }
+        map
+    }
+}
+
+/// Later pairs overwrite earlier ones with the same key.
+impl<K: Hash + Eq, V, const N: usize> From<[(K, V); N]> for Map<K, V> {
+    fn from(pairs: [(K, V); N]) -> Self {
+        pairs.into_iter().collect()
+    }
+}
+
+impl<K: Hash + Eq, V, S: BuildHasher> Extend<(K, V)> for Map<K, V, S> {
+    fn extend<I: IntoIterator<Item = (K, V)>>(&mut self, iter: I) {
+        let iter = iter.into_iter();
+        self.reserve(iter.size_hint().0);
+        for (key, value) in iter {
+            self.insert(key, value);
+        }
+    }
+}
+
+impl<'a, K: Hash + Eq + Copy, V: Copy, S: BuildHasher> Extend<(&'a K, &'a V)> for Map<K, V, S> {
+    fn extend<I: IntoIterator<Item = (&'a K, &'a V)>>(&mut self, iter: I) {
+        self.extend(iter.into_iter().map(|(&k, &v)| (k, v)));
+    }
+}
+
+impl<K, Q, V, S> Index<&Q> for Map<K, V, S>
+where
+    K: Hash + Eq + Borrow<Q>,
+    Q: Hash + Eq + ?Sized,
+    S: BuildHasher,
+{
+    type Output = V;
+
+    fn index(&self, key: &Q) -> &V {
+        self.get(key).expect("key not present in map")
+    }
+}
+
+impl<K: Hash + Eq + Debug, V: Debug, S: BuildHasher> Debug for Map<K, V, S> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_map().entries(self.iter()).finish()
+    }
+}
+
+/// Builds a `Map` from pairs pushed one at a time, with settings that the
+/// constructors leave at their defaults.
+pub struct MapBuilder<K: Hash + Eq, V> {
+    map: Map<K, V>,
+}
+
+impl<K: Hash + Eq, V> MapBuilder<K, V> {
+    pub fn new() -> Self {
+        Self { map: Map::new() }
+    }
+
+    /// Sets the fraction of slots, in `(0.0, 1.0)`, that may fill before the
+    /// map expands. A lower factor spends memory to keep probe runs short.
+    pub fn with_load_factor(mut self, load_factor: f64) -> Self {
+        assert!(
+            load_factor > 0.0 && load_factor < 1.0,
+            "load factor must be between 0 and 1"
+        );
+        self.map.max_load_factor = load_factor;
+        self
+    }
+
+    pub fn push(&mut self, key: K, value: V) {
+        self.map.insert(key, value);
+    }
+
+    pub fn finish(self) -> Map<K, V> {
+        self.map
+    }
+}
+
+impl<K: Hash + Eq, V> Default for MapBuilder<K, V> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// A hash set backed by a `Map` with `()` values, which take no space.
+#[derive(Clone)]
+pub struct Set<T: Hash + Eq, S = RandomState> {
+    map: Map<T, (), S>,
+}
+
+impl<T: Hash + Eq> Set<T> {
+    pub fn new() -> Self {
+        Self { map: Map::new() }
+    }
+}
+
+impl<T: Hash + Eq, S: BuildHasher> Set<T, S> {
+    /// Creates a set that hashes its values with `hasher`.
+    pub fn with_hasher(hasher: S) -> Self {
+        Self {
+            map: Map::with_hasher(hasher),
+        }
+    }
+
+    /// Adds `value`, returning false if it was already present.
+    pub fn insert(&mut self, value: T) -> bool {
+        self.map.insert(value, ()).is_none()
+    }
+
+    pub fn contains<Q>(&self, value: &Q) -> bool
+    where
+        T: Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+    {
+        self.map.contains_key(value)
+    }
+
+    /// Removes `value`, returning whether it was present.
+    pub fn remove<Q>(&mut self, value: &Q) -> bool
+    where
+        T: Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+    {
+        self.map.delete(value).is_some()
+    }
+
+    pub fn len(&self) -> usize {
+        self.map.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.map.is_empty()
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &T> {
+        self.map.iter().map(|(value, _)| value)
+    }
+
+    /// Returns whether every value of `self` is also in `other`.
+    pub fn is_subset(&self, other: &Self) -> bool {
+        self.len() <= other.len() && self.iter().all(|value| other.contains(value))
+    }
+
+    /// Returns whether `self` and `other` have no values in common.
+    pub fn is_disjoint(&self, other: &Self) -> bool {
+        let (small, large) = if self.len() <= other.len() {
+            (self, other)
+        } else {
+            (other, self)
+        };
+        !small.iter().any(|value| large.contains(value))
+    }
+}
+
+impl<T: Hash + Eq, S: BuildHasher + Default> Default for Set<T, S> {
+    fn default() -> Self {
+        Self {
+            map: Map::default(),
+        }
+    }
+}
+
+impl<T: Hash + Eq, S: BuildHasher + Default> FromIterator<T> for Set<T, S> {
+    fn from_iter<I: IntoIterator<Item = T>>(iter: I) -> Self {
+        Self {
+            map: iter.into_iter().map(|value| (value, ())).collect(),
+        }
+    }
+}
+
+impl<T: Hash + Eq, S: BuildHasher> Extend<T> for Set<T, S> {
+    fn extend<I: IntoIterator<Item = T>>(&mut self, iter: I) {
+        self.map.extend(iter.into_iter().map(|value| (value, ())));
+    }
+}
+
+impl<T: Hash + Eq, S> IntoIterator for Set<T, S> {
+    type Item = T;
+    type IntoIter = iter::Map<IntoIter<T, ()>, fn((T, ())) -> T>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.map.into_iter().map(|(value, ())| value)
+    }
+}
+
+pub enum Entry<'a, K: Hash + Eq, V, S = RandomState> {
+    Occupied(OccupiedEntry<'a, K, V, S>),
+    Vacant(VacantEntry<'a, K, V, S>),
+}
+
+pub struct OccupiedEntry<'a, K: Hash + Eq, V, S = RandomState> {
+    map: &'a mut Map<K, V, S>,
+    index: usize,
+}
+
+pub struct VacantEntry<'a, K: Hash + Eq, V, S = RandomState> {
+    map: &'a mut Map<K, V, S>,
+    key: K,
+    hash: u64,
+    index: usize,
+}
+
+impl<'a, K: Hash + Eq, V, S: BuildHasher> Entry<'a, K, V, S> {
+    pub fn or_insert(self, default: V) -> &'a mut V {
+        self.or_insert_with(|| default)
+    }
+
+    pub fn or_insert_with<F: FnOnce() -> V>(self, f: F) -> &'a mut V {
+        match self {
+            Self::Occupied(entry) => entry.into_mut(),
+            Self::Vacant(entry) => entry.insert(f()),
+        }
+    }
+
+    pub fn and_modify<F: FnOnce(&mut V)>(mut self, f: F) -> Self {
+        if let Self::Occupied(entry) = &mut self {
+            f(entry.get_mut());
+        }
+        self
+    }
+
+    pub fn key(&self) -> &K {
+        match self {
+            Self::Occupied(entry) => entry.key(),
+            Self::Vacant(entry) => entry.key(),
+        }
+    }
+}
+
+impl<'a, K: Hash + Eq, V, S: BuildHasher> OccupiedEntry<'a, K, V, S> {
+    pub fn key(&self) -> &K {
+        &self.map.slots[self.index].as_ref().unwrap().key
+    }
+
+    pub fn get(&self) -> &V {
+        &self.map.slots[self.index].as_ref().unwrap().value
+    }
+
+    pub fn get_mut(&mut self) -> &mut V {
+        &mut self.map.slots[self.index].as_mut().unwrap().value
+    }
+
+    pub fn into_mut(self) -> &'a mut V {
+        &mut self.map.slots[self.index].as_mut().unwrap().value
+    }
+
+    pub fn insert(&mut self, value: V) -> V {
+        mem::replace(self.get_mut(), value)
+    }
+
+    pub fn remove(self) -> V {
+        self.map.remove_at(self.index).value
+    }
+}
+
+impl<'a, K: Hash + Eq, V, S: BuildHasher> VacantEntry<'a, K, V, S> {
+    pub fn key(&self) -> &K {
+        &self.key
+    }
+
+    pub fn insert(self, value: V) -> &'a mut V {
+        let (key, hash) = (self.key, self.hash);
+        self.map.occupy(self.index, Bucket { key, value, hash });
+        &mut self.map.slots[self.index].as_mut().unwrap().value
+    }
+}
+
+/// Returned by `Map::try_insert` when the key is already present. The map is
+/// left unchanged, and `value` is the one that was not inserted.
+pub struct OccupiedError<'a, K: Hash + Eq, V, S = RandomState> {
+    pub entry: OccupiedEntry<'a, K, V, S>,
+    pub value: V,
+}
+
+impl<'a, K: Hash + Eq + Debug, V: Debug, S: BuildHasher> Debug for OccupiedError<'a, K, V, S> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("OccupiedError")
+            .field("key", self.entry.key())
+            .field("old_value", self.entry.get())
+            .field("new_value", &self.value)
+            .finish()
+    }
+}
+
+impl<'a, K: Hash + Eq + Debug, V: Debug, S: BuildHasher> fmt::Display
+    for OccupiedError<'a, K, V, S>
+{
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "failed to insert {:?}, key {:?} already exists with value {:?}",
+            self.value,
+            self.entry.key(),
+            self.entry.get(),
+        )
+    }
+}
+
+impl<'a, K: Hash + Eq + Debug, V: Debug, S: BuildHasher> Error for OccupiedError<'a, K, V, S> {}
+
 pub struct Iter<'a, K: Hash + Eq, V> {
-    map: &'a Map<K, V>,
+    slots: &'a [Slot<Bucket<K, V>>],
     i: usize,
 }
 
@@ -176,8 +920,8 @@ impl<'a, K: Hash + Eq, V> Iterator for Iter<'a, K, V> {
     type Item = (&'a K, &'a V);
 
     fn next(&mut self) -> Option<Self::Item> {
-        while self.i < self.map.slots.len() {
-            if let Slot::Occupied(entry) = &self.map.slots[self.i] {
+        while self.i < self.slots.len() {
+            if let Slot::Occupied(entry) = &self.slots[self.i] {
                 self.i += 1;
                 return Some((&entry.key, &entry.value));
             }
@@ -188,7 +932,7 @@ impl<'a, K: Hash + Eq, V> Iterator for Iter<'a, K, V> {
 }
 
 pub struct IterMut<'a, K: Hash + Eq, V> {
-    map: &'a mut Map<K, V>,
+    slots: &'a mut [Slot<Bucket<K, V>>],
     i: usize,
 }
 
@@ -196,10 +940,10 @@ impl<'a, K: Hash + Eq, V> Iterator for IterMut<'a, K, V> {
     type Item = (&'a K, &'a mut V);
 
     fn next(&mut self) -> Option<Self::Item> {
-        while self.i < self.map.slots.len() {
-            if let Slot::Occupied(entry) = &mut self.map.slots[self.i] {
+        while self.i < self.slots.len() {
+            if let Slot::Occupied(entry) = &mut self.slots[self.i] {
                 self.i += 1;
-                let entry: *mut Entry<K, V> = entry;
+                let entry: *mut Bucket<K, V> = entry;
                 return Some(unsafe { (&(*entry).key, &mut (*entry).value) });
             }
             self.i += 1;
@@ -209,7 +953,7 @@ impl<'a, K: Hash + Eq, V> Iterator for IterMut<'a, K, V> {
 }
 
 pub struct IntoIter<K: Hash + Eq, V> {
-    map: Map<K, V>,
+    slots: Vec<Slot<Bucket<K, V>>>,
     i: usize,
 }
 
@@ -217,8 +961,8 @@ impl<K: Hash + Eq, V> Iterator for IntoIter<K, V> {
     type Item = (K, V);
 
     fn next(&mut self) -> Option<Self::Item> {
-        while self.i < self.map.slots.len() {
-            if let Slot::Occupied(entry) = mem::replace(&mut self.map.slots[self.i], Slot::Empty) {
+        while self.i < self.slots.len() {
+            if let Slot::Occupied(entry) = mem::replace(&mut self.slots[self.i], Slot::Empty) {
                 self.i += 1;
                 return Some((entry.key, entry.value));
             }
@@ -228,12 +972,79 @@ impl<K: Hash + Eq, V> Iterator for IntoIter<K, V> {
     }
 }
 
-impl<K: Hash + Eq, V> IntoIterator for Map<K, V> {
+impl<K: Hash + Eq, V, S> IntoIterator for Map<K, V, S> {
     type Item = (K, V);
     type IntoIter = IntoIter<K, V>;
 
     fn into_iter(self) -> Self::IntoIter {
-        IntoIter { map: self, i: 0 }
+        IntoIter {
+            slots: self.slots,
+            i: 0,
+        }
+    }
+}
+
+#[cfg(feature = "serde")]
+mod serde_impls {
+    use std::{
+        fmt,
+        hash::{BuildHasher, Hash},
+        marker::PhantomData,
+    };
+
+    use serde::{
+        Deserialize, Deserializer, Serialize, Serializer,
+        de::{MapAccess, Visitor},
+    };
+
+    use super::Map;
+    use crate::MAX_PREALLOCATED;
+
+    impl<K, V, S> Serialize for Map<K, V, S>
+    where
+        K: Hash + Eq + Serialize,
+        V: Serialize,
+        S: BuildHasher,
+    {
+        fn serialize<T: Serializer>(&self, serializer: T) -> Result<T::Ok, T::Error> {
+            serializer.collect_map(self.iter())
+        }
+    }
+
+    impl<'de, K, V, S> Deserialize<'de> for Map<K, V, S>
+    where
+        K: Hash + Eq + Deserialize<'de>,
+        V: Deserialize<'de>,
+        S: BuildHasher + Default,
+    {
+        fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+            deserializer.deserialize_map(MapVisitor(PhantomData))
+        }
+    }
+
+    struct MapVisitor<K: Hash + Eq, V, S>(PhantomData<Map<K, V, S>>);
+
+    impl<'de, K, V, S> Visitor<'de> for MapVisitor<K, V, S>
+    where
+        K: Hash + Eq + Deserialize<'de>,
+        V: Deserialize<'de>,
+        S: BuildHasher + Default,
+    {
+        type Value = Map<K, V, S>;
+
+        fn expecting(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+            f.write_str("a map")
+        }
+
+        /// Later duplicates of a key overwrite earlier ones.
+        fn visit_map<A: MapAccess<'de>>(self, mut access: A) -> Result<Self::Value, A::Error> {
+            let cap = access.size_hint().unwrap_or(0).min(MAX_PREALLOCATED);
+            let mut map = Map::with_capacity_and_hasher(cap, S::default());
+            while let Some((key, value)) = access.next_entry()? {
+                map.insert(key, value);
+            }
+            Ok(map)
+        }
     }
 }
 
@@ -242,6 +1053,10 @@ mod tests {
     use super::*;
     use std::collections::HashMap as StdHashMap;
 
+    fn keys_with_home(map: &Map<i32, i32>, home: usize, n: usize) -> Vec<i32> {
+        (0..).filter(|k| map.hash(k) == home).take(n).collect()
+    }
+
     #[test]
     fn test_new() {
         let map: Map<String, i32> = Map::new();
@@ -266,6 +1081,22 @@ mod tests {
         assert_eq!(map.get(&"one".to_string()), Some(&11));
     }
 
+    #[test]
+    fn test_get_many_mut() {
+        let mut map: Map<_, _> = (0..100).map(|i| (i.to_string(), i)).collect();
+        let [a, b, c] = map.get_many_mut(["1", "50", "99"]).unwrap();
+        mem::swap(a, b);
+        *c += 1;
+        assert_eq!(map.get("1"), Some(&50));
+        assert_eq!(map.get("50"), Some(&1));
+        assert_eq!(map.get("99"), Some(&100));
+
+        assert!(map.get_many_mut(["1", "2", "1"]).is_none());
+        assert!(map.get_many_mut(["1", "missing"]).is_none());
+        assert_eq!(map.get_many_mut::<str, 0>([]), Some([]));
+        assert_eq!(map.get("1"), Some(&50));
+    }
+
     #[test]
     fn test_get_mut() {
         let mut map = Map::new();
@@ -301,20 +1132,22 @@ mod tests {
     fn test_delete_and_probe() {
         let mut map: Map<i32, i32> = Map::new();
 
-        let len = map.slots.len();
-        let key1 = 1;
-        let key2 = key1 + len as i32;
+        let home = 3;
+        let keys = keys_with_home(&map, home, 2);
+        let (key1, key2) = (keys[0], keys[1]);
 
         map.insert(key1, 10);
-        map.insert(key2, 20); // key2 会被放在 key1 后面的槽位
+        map.insert(key2, 20); // key2 与 key1 同一个理想位置，会被放在 key1 后面的槽位
+        assert!(matches!(&map.slots[home + 1], Slot::Occupied(entry) if entry.key == key2));
 
         assert_eq!(map.get(&key1), Some(&10));
         assert_eq!(map.get(&key2), Some(&20));
 
-        // 删除 key1，留下墓碑
+        // 删除 key1 后 key2 回移到 key1 的槽位，不留空洞
         map.delete(&key1);
+        assert!(matches!(&map.slots[home], Slot::Occupied(entry) if entry.key == key2));
+        assert!(!map.slots[home + 1].is_occupied());
 
-        // 确认 key2 仍然可以被找到，证明探查越过了墓碑
         assert_eq!(map.get(&key2), Some(&20));
     }
 
@@ -461,4 +1294,863 @@ mod tests {
         }
         assert_eq!(count, 2);
     }
+
+    #[test]
+    fn test_probe_histogram() {
+        let mut map = Map::new();
+        assert!(map.probe_histogram().is_empty());
+
+        // 三个键共享同一个起始槽位，依次占据距离 0、1、2
+        let home = map.hash(&0);
+        for k in keys_with_home(&map, home, 3) {
+            map.insert(k, k);
+        }
+        assert_eq!(map.probe_histogram(), vec![1, 1, 1]);
+
+        // 起始于下一个槽位的键被挤到距离 2 处
+        let next = keys_with_home(&map, (home + 1) % map.slots.len(), 1)[0];
+        map.insert(next, next);
+        assert_eq!(map.probe_histogram(), vec![1, 1, 2]);
+    }
+
+    #[test]
+    fn test_overhead_ratio() {
+        let mut map = Map::new();
+        assert_eq!(map.overhead_ratio(), f64::INFINITY);
+
+        // 在不扩容的前提下，填充得越满，开销比越低
+        let mut last = f64::INFINITY;
+        for i in 0..50u64 {
+            map.insert(i, i);
+            let ratio = map.overhead_ratio();
+            assert!(ratio < last);
+            last = ratio;
+        }
+        assert!(last > 1.0);
+    }
+
+    #[test]
+    fn test_remove_chain() {
+        let mut map = Map::new();
+        let home = map.hash(&0);
+        let len = map.slots.len();
+        let chain = keys_with_home(&map, home, 3);
+        let far = (0..)
+            .find(|k| (8..len - 8).contains(&((map.hash(k) + len - home) % len)))
+            .unwrap();
+
+        for &k in &chain {
+            map.insert(k, k * 10);
+        }
+        map.insert(far, far * 10);
+
+        // 删除中间的键，整条探查链都被取出
+        let removed = map.remove_chain(&chain[1]);
+        assert_eq!(
+            removed,
+            chain.iter().map(|&k| (k, k * 10)).collect::<Vec<_>>()
+        );
+        assert_eq!(map.count, 1);
+        assert_eq!(map.get(&far), Some(&(far * 10)));
+        for k in &chain {
+            assert_eq!(map.get(k), None);
+        }
+        assert!(map.remove_chain(&chain[0]).is_empty());
+
+        // 谓词版本只驱逐选中的元素，其余的重新插入
+        for &k in &chain {
+            map.insert(k, k * 10);
+        }
+        let removed = map.remove_chain_if(&chain[0], |k, _| *k == chain[1]);
+        assert_eq!(removed, vec![(chain[1], chain[1] * 10)]);
+        assert_eq!(map.count, 3);
+        assert_eq!(map.get(&chain[0]), Some(&(chain[0] * 10)));
+        assert_eq!(map.get(&chain[1]), None);
+        assert_eq!(map.get(&chain[2]), Some(&(chain[2] * 10)));
+        assert_eq!(map.get(&far), Some(&(far * 10)));
+    }
+
+    #[test]
+    fn test_resize_count() {
+        let mut map = Map::new();
+        // 恰好插入到负载因子阈值之下，不应扩容
+        let threshold = (INITIAL_SIZE as f64 * LOAD_FACTOR) as usize + 1;
+        for i in 0..threshold {
+            map.insert(i, i);
+        }
+        assert_eq!(map.resize_count(), 0);
+
+        map.insert(threshold, threshold);
+        assert_eq!(map.resize_count(), 1);
+    }
+
+    #[test]
+    fn test_len_and_is_empty() {
+        let mut map = Map::new();
+        assert_eq!(map.len(), 0);
+        assert!(map.is_empty());
+
+        map.insert("a".to_string(), 1);
+        map.insert("b".to_string(), 2);
+        assert_eq!(map.len(), 2);
+        assert!(!map.is_empty());
+
+        // 覆盖已有的键不改变长度
+        map.insert("a".to_string(), 10);
+        assert_eq!(map.len(), 2);
+
+        map.delete(&"a".to_string());
+        map.delete(&"missing".to_string());
+        assert_eq!(map.len(), 1);
+        map.delete(&"b".to_string());
+        assert_eq!(map.len(), 0);
+        assert!(map.is_empty());
+    }
+
+    #[test]
+    fn test_clear() {
+        let mut map = Map::new();
+        for i in 0..100 {
+            map.insert(i, i);
+        }
+        for i in 0..10 {
+            map.delete(&i);
+        }
+        let len = map.slots.len();
+
+        map.clear();
+        assert_eq!(map.len(), 0);
+        assert_eq!(map.slots.len(), len);
+        // 所有槽位都应变回空槽
+        assert!(map.slots.iter().all(|s| matches!(s, Slot::Empty)));
+        for i in 0..100 {
+            assert_eq!(map.get(&i), None);
+        }
+
+        map.insert(1, 1);
+        assert_eq!(map.get(&1), Some(&1));
+    }
+
+    #[test]
+    fn test_iter_skips_deleted() {
+        let mut map = Map::new();
+        let mut std_map = StdHashMap::new();
+        for i in 0..20 {
+            map.insert(i, i * 2);
+            std_map.insert(i, i * 2);
+        }
+        for i in (0..20).step_by(3) {
+            map.delete(&i);
+            std_map.remove(&i);
+        }
+
+        let mut count = 0;
+        for (k, v) in map.iter() {
+            assert_eq!(std_map.get(k), Some(v));
+            count += 1;
+        }
+        assert_eq!(count, std_map.len());
+    }
+
+    #[test]
+    fn test_into_iter_collect() {
+        let mut map = Map::new();
+        for i in 0..30 {
+            map.insert(i, i.to_string());
+        }
+        for i in 10..20 {
+            map.delete(&i);
+        }
+
+        let mut entries: Vec<_> = map.into_iter().collect();
+        entries.sort();
+        let expected: Vec<_> = (0..10).chain(20..30).map(|i| (i, i.to_string())).collect();
+        assert_eq!(entries, expected);
+    }
+
+    #[test]
+    fn test_contains_key() {
+        let mut map = Map::new();
+        map.insert("a".to_string(), 1);
+        map.insert("b".to_string(), 2);
+        assert!(map.contains_key(&"a".to_string()));
+        assert!(!map.contains_key(&"c".to_string()));
+
+        // 删除后后面的元素会回移，被删的键不应再被判定为存在，其余的键仍能找到
+        map.delete(&"a".to_string());
+        assert!(!map.contains_key(&"a".to_string()));
+        assert!(map.contains_key(&"b".to_string()));
+    }
+
+    #[test]
+    fn test_entry() {
+        let mut map = Map::new();
+        for word in "a b a c b a".split(' ') {
+            *map.entry(word).or_insert(0) += 1;
+        }
+        assert_eq!(map.len(), 3);
+        assert_eq!(map.get(&"a"), Some(&3));
+        assert_eq!(map.get(&"b"), Some(&2));
+        assert_eq!(map.get(&"c"), Some(&1));
+
+        map.entry("a").and_modify(|v| *v *= 10).or_insert(0);
+        map.entry("d").and_modify(|v| *v *= 10).or_insert(4);
+        assert_eq!(map.get(&"a"), Some(&30));
+        assert_eq!(map.get(&"d"), Some(&4));
+        assert_eq!(*map.entry("e").or_insert_with(|| 5), 5);
+        assert_eq!(map.entry("e").key(), &"e");
+
+        if let Entry::Occupied(entry) = map.entry("e") {
+            assert_eq!(entry.remove(), 5);
+        }
+        assert_eq!(map.get(&"e"), None);
+        assert_eq!(map.len(), 4);
+    }
+
+    #[test]
+    fn test_get_or_insert_with() {
+        use std::cell::Cell;
+
+        let calls = Cell::new(0);
+        let fill = |i: i32| {
+            calls.set(calls.get() + 1);
+            i * 10
+        };
+        let mut map = Map::new();
+        for round in 0..3 {
+            for i in 0..1000 {
+                *map.get_or_insert_with(i, || fill(i)) += 1;
+            }
+            assert_eq!(calls.get(), 1000, "round {round} refilled a hit");
+        }
+        assert_eq!(map.len(), 1000);
+        assert_eq!(map.get(&7), Some(&73));
+    }
+
+    #[test]
+    fn test_insert_and_get_returns_value() {
+        let mut map = Map::new();
+        // 插入过程中会多次扩容，返回的引用指向扩容后的位置
+        for i in 0..1000 {
+            map.insert_and_get(i, vec![i]).push(-i);
+        }
+        map.insert_and_get(7, Vec::new()).push(70);
+        assert_eq!(map.len(), 1000);
+        assert_eq!(map.get(&7), Some(&vec![70]));
+        assert_eq!(map.get(&999), Some(&vec![999, -999]));
+    }
+
+    #[test]
+    fn test_try_insert() {
+        let mut map = Map::new();
+        *map.try_insert("a".to_string(), 1).unwrap() += 10;
+        assert_eq!(map.get("a"), Some(&11));
+
+        let error = map.try_insert("a".to_string(), 2).unwrap_err();
+        assert_eq!(error.entry.key(), "a");
+        assert_eq!(error.entry.get(), &11);
+        assert_eq!(error.value, 2);
+        assert_eq!(
+            error.to_string(),
+            r#"failed to insert 2, key "a" already exists with value 11"#
+        );
+        assert_eq!(map.len(), 1);
+        assert_eq!(map.get("a"), Some(&11));
+    }
+
+    #[test]
+    fn test_entry_expand() {
+        let mut map = Map::new();
+        // 填充到阈值，下一次插入会触发扩容
+        let threshold = (INITIAL_SIZE as f64 * LOAD_FACTOR) as usize + 1;
+        for i in 0..threshold {
+            map.insert(i, i);
+        }
+        assert_eq!(map.slots.len(), INITIAL_SIZE);
+
+        *map.entry(threshold).or_insert(0) += 1;
+        assert_eq!(map.slots.len(), INITIAL_SIZE * EXPANSION_FACTOR);
+        assert_eq!(map.get(&threshold), Some(&1));
+        for i in 0..threshold {
+            assert_eq!(map.get(&i), Some(&i));
+        }
+    }
+
+    #[test]
+    fn test_with_capacity() {
+        let mut map = Map::with_capacity(10_000);
+        let len = map.slots.len();
+        assert_eq!(len, 16384);
+        for i in 0..9000 {
+            map.insert(i, i);
+        }
+        assert_eq!(map.slots.len(), len);
+        assert_eq!(map.resize_count(), 0);
+
+        assert_eq!(Map::<i32, i32>::with_capacity(0).slots.len(), INITIAL_SIZE);
+    }
+
+    #[test]
+    fn test_load_factor() {
+        let mut map = Map::new();
+        assert_eq!(map.load_factor(), 0.0);
+        for i in 0..map.capacity() {
+            map.insert(i, i);
+        }
+        assert_eq!(map.resize_count(), 0);
+        assert!(map.load_factor() > 0.85 && map.load_factor() < 0.9);
+        map.insert(INITIAL_SIZE, 0);
+        map.insert(INITIAL_SIZE + 1, 0);
+        assert_eq!(map.resize_count(), 1);
+        assert!(map.load_factor() < 0.5);
+    }
+
+    #[test]
+    fn test_capacity_and_reserve() {
+        let mut map = Map::new();
+        assert_eq!(map.capacity(), 57);
+        map.reserve(10);
+        assert_eq!(map.slots.len(), INITIAL_SIZE);
+
+        for i in 0..10 {
+            map.insert(i, i);
+        }
+        map.reserve(5000);
+        let len = map.slots.len();
+        assert!(map.capacity() >= 5010);
+        assert_eq!(map.resize_count(), 1);
+
+        // 预留之后插入预留的数量，不应再发生扩容
+        for i in 10..5010 {
+            map.insert(i, i);
+        }
+        assert_eq!(map.slots.len(), len);
+        assert_eq!(map.resize_count(), 1);
+        for i in 0..5010 {
+            assert_eq!(map.get(&i), Some(&i));
+        }
+    }
+
+    /// Returns how many entries fit before the table first doubles.
+    fn entries_before_expansion(mut map: Map<i32, i32>) -> usize {
+        let mut i = 0;
+        while map.slots.len() == INITIAL_SIZE {
+            map.insert(i, i);
+            i += 1;
+        }
+        i as usize - 1
+    }
+
+    #[test]
+    fn test_builder_load_factor() {
+        let default = entries_before_expansion(MapBuilder::new().finish());
+        let sparse = MapBuilder::new().with_load_factor(0.5).finish();
+        assert_eq!(sparse.capacity(), INITIAL_SIZE / 2);
+        let sparse = entries_before_expansion(sparse);
+        assert_eq!(sparse, INITIAL_SIZE / 2);
+        assert!(sparse < default);
+
+        let mut builder = MapBuilder::new().with_load_factor(0.25);
+        for i in 0..1000 {
+            builder.push(i, i);
+        }
+        let mut map = builder.finish();
+        assert_eq!(map.len(), 1000);
+        assert!(map.len() as f64 / map.slots.len() as f64 <= 0.25);
+        // 收缩和预留也按配置的负载因子计算
+        map.shrink_to_fit();
+        assert_eq!(map.slots.len(), 4096);
+    }
+
+    #[test]
+    fn test_builder_load_factor_near_one() {
+        let mut builder = MapBuilder::new().with_load_factor(0.99);
+        for i in 0..INITIAL_SIZE as i32 * 3 {
+            builder.push(i, i);
+            // 每次插入之后都要留有空槽，retain 和探查都依赖这一点
+            assert!(builder.map.slots.iter().any(|slot| !slot.is_occupied()));
+        }
+        let mut map = builder.finish();
+        map.retain(|k, _| k % 2 == 0);
+        assert_eq!(map.len(), INITIAL_SIZE * 3 / 2);
+        assert_eq!(map.get(&2), Some(&2));
+        assert_eq!(map.get(&3), None);
+    }
+
+    #[test]
+    #[should_panic(expected = "load factor must be between 0 and 1")]
+    fn test_builder_rejects_zero_load_factor() {
+        MapBuilder::<i32, i32>::new().with_load_factor(0.0);
+    }
+
+    #[test]
+    #[should_panic(expected = "load factor must be between 0 and 1")]
+    fn test_builder_rejects_full_load_factor() {
+        MapBuilder::<i32, i32>::new().with_load_factor(1.0);
+    }
+
+    #[test]
+    fn test_memory_footprint() {
+        let mut map: Map<u64, u64> = Map::new();
+        let initial = map.memory_footprint();
+        assert_eq!(
+            map.memory_footprint(),
+            map.slots.len() * mem::size_of::<Slot<Bucket<u64, u64>>>()
+        );
+
+        for i in 0..2000 {
+            map.insert(i, i);
+        }
+        let grown = map.memory_footprint();
+        assert!(grown > initial);
+
+        for i in 100..2000 {
+            map.delete(&i);
+        }
+        assert_eq!(map.memory_footprint(), grown);
+        map.shrink_to_fit();
+        assert!(map.memory_footprint() < grown);
+    }
+
+    #[test]
+    fn test_shrink_to_fit() {
+        let mut map = Map::new();
+        for i in 0..1000 {
+            map.insert(i, i);
+        }
+        for i in 10..1000 {
+            map.delete(&i);
+        }
+        let len = map.slots.len();
+
+        map.shrink_to_fit();
+        assert_eq!(map.slots.len(), INITIAL_SIZE);
+        assert!(map.slots.len() < len);
+        assert_eq!(map.len(), 10);
+        for i in 0..10 {
+            assert_eq!(map.get(&i), Some(&i));
+        }
+    }
+
+    #[test]
+    fn test_default() {
+        let map = Map::<String, i32>::default();
+        assert_eq!(map.len(), 0);
+        assert!(map.is_empty());
+    }
+
+    #[test]
+    fn test_clone() {
+        let mut map = Map::new();
+        for i in 0..100 {
+            map.insert(i.to_string(), i);
+        }
+        map.delete(&"0".to_string());
+        let clone = map.clone();
+
+        // 修改原 map 不影响克隆出的 map
+        map.insert("1".to_string(), -1);
+        map.delete(&"2".to_string());
+        map.insert("new".to_string(), 0);
+
+        assert_eq!(clone.len(), 99);
+        assert_eq!(clone.get(&"0".to_string()), None);
+        assert_eq!(clone.get(&"1".to_string()), Some(&1));
+        assert_eq!(clone.get(&"2".to_string()), Some(&2));
+        assert_eq!(clone.get(&"new".to_string()), None);
+        for i in 1..100 {
+            assert_eq!(clone.get(&i.to_string()), Some(&i));
+        }
+    }
+
+    #[test]
+    fn test_debug() {
+        let mut map = Map::new();
+        map.insert("a".to_string(), 1);
+        map.insert("b".to_string(), 2);
+
+        let output = format!("{:?}", map);
+        assert!(output.contains("\"a\": 1"));
+        assert!(output.contains("\"b\": 2"));
+        assert_eq!(format!("{:?}", Map::<i32, i32>::new()), "{}");
+    }
+
+    #[test]
+    fn test_eq() {
+        let mut a = Map::new();
+        let mut b = Map::new();
+        for i in 0..100 {
+            a.insert(i, i * 2);
+            b.insert(99 - i, (99 - i) * 2);
+        }
+        assert_eq!(a, b);
+
+        b.insert(0, 1);
+        assert_ne!(a, b);
+        b.insert(0, 0);
+        assert_eq!(a, b);
+
+        b.delete(&0);
+        assert_ne!(a, b);
+    }
+
+    #[test]
+    fn test_from_iter() {
+        let pairs = vec![
+            ("a".to_string(), 1),
+            ("b".to_string(), 2),
+            ("a".to_string(), 3),
+        ];
+        let map: Map<_, _> = pairs.into_iter().collect();
+        assert_eq!(map.len(), 2);
+        assert_eq!(map.get(&"a".to_string()), Some(&3));
+        assert_eq!(map.get(&"b".to_string()), Some(&2));
+
+        let map: Map<_, _> = (0..1000).map(|i| (i, i)).collect();
+        assert_eq!(map.len(), 1000);
+        assert_eq!(map.resize_count(), 0);
+    }
+
+    #[test]
+    fn test_from_array() {
+        let map = Map::from([("a", 1), ("b", 2), ("a", 3)]);
+        assert_eq!(map.len(), 2);
+        assert_eq!(map.get("a"), Some(&3));
+        assert_eq!(map.get("b"), Some(&2));
+        assert_eq!(map.resize_count(), 0);
+
+        let map: Map<i32, i32> = Map::from([]);
+        assert!(map.is_empty());
+    }
+
+    #[test]
+    fn test_append() {
+        let mut map: Map<_, _> = (0..600).map(|i| (i, i)).collect();
+        let mut other: Map<_, _> = (500..1500).map(|i| (i, -i)).collect();
+        let resizes = map.resize_count();
+        let slots = other.slots.as_ptr();
+        map.append(&mut other);
+
+        assert_eq!(map.len(), 1500);
+        assert_eq!(map.get(&499), Some(&499));
+        assert_eq!(map.get(&500), Some(&-500));
+        assert_eq!(map.get(&1499), Some(&-1499));
+        // 先一次性预留，不会边插入边翻倍
+        assert_eq!(map.resize_count(), resizes + 1);
+
+        // other 的槽位被原地清空，分配保留
+        assert!(other.is_empty());
+        assert_eq!(other.slots.as_ptr(), slots);
+        assert!(other.slots.iter().all(|slot| !slot.is_occupied()));
+        other.insert(1, 1);
+        assert_eq!(other.get(&1), Some(&1));
+    }
+
+    #[test]
+    fn test_extend() {
+        let mut map = Map::new();
+        map.insert(0, 0);
+        map.insert(1, 1);
+
+        map.extend((1..500).map(|i| (i, i * 2)));
+        assert_eq!(map.len(), 500);
+        assert_eq!(map.get(&0), Some(&0));
+        assert_eq!(map.get(&1), Some(&2));
+        assert_eq!(map.get(&499), Some(&998));
+
+        let other: Map<_, _> = (500..600).map(|i| (i, i)).collect();
+        map.extend(other.iter());
+        assert_eq!(map.len(), 600);
+        assert_eq!(map.get(&550), Some(&550));
+    }
+
+    #[test]
+    fn test_index() {
+        let mut map = Map::new();
+        map.insert("a".to_string(), 1);
+        assert_eq!(map[&"a".to_string()], 1);
+    }
+
+    #[test]
+    #[should_panic(expected = "key not present")]
+    fn test_index_missing() {
+        let map: Map<String, i32> = Map::new();
+        let _ = map[&"a".to_string()];
+    }
+
+    #[test]
+    fn test_borrowed_lookup() {
+        let mut map: Map<String, i32> = Map::new();
+        map.insert("key1".to_string(), 1);
+        map.insert("key2".to_string(), 2);
+        assert_eq!(map.get("key1"), Some(&1));
+        assert!(map.contains_key("key2"));
+        assert_eq!(map["key2"], 2);
+        *map.get_mut("key1").unwrap() += 10;
+        assert_eq!(map.delete("key1"), Some(11));
+        assert_eq!(map.get("key1"), None);
+        assert_eq!(map.len(), 1);
+    }
+
+    #[test]
+    fn test_with_hasher() {
+        use std::hash::{BuildHasherDefault, DefaultHasher};
+
+        type Fixed = BuildHasherDefault<DefaultHasher>;
+        let build = || {
+            let mut map = Map::with_hasher(Fixed::default());
+            for i in 0..1000 {
+                map.insert(i, i * 2);
+            }
+            map
+        };
+        let (a, b) = (build(), build());
+        assert_eq!(a.get(&500), Some(&1000));
+        assert_eq!(a.hash(&7), b.hash(&7));
+        assert!(a.iter().eq(b.iter()));
+    }
+
+    #[test]
+    fn test_with_capacity_and_hasher() {
+        use std::hash::{BuildHasherDefault, DefaultHasher};
+
+        let build = || {
+            let mut map =
+                Map::with_capacity_and_hasher(500, BuildHasherDefault::<DefaultHasher>::default());
+            for i in 0..500 {
+                map.insert(i, i);
+            }
+            map
+        };
+        let (a, b) = (build(), build());
+        assert_eq!(a.resize_count(), 0);
+        assert_eq!(a.hasher().hash_one(42), b.hasher().hash_one(42));
+        assert!(a.iter().eq(b.iter()));
+        assert_eq!(a.get(&499), Some(&499));
+    }
+
+    #[test]
+    fn test_update_all() {
+        let mut map: Map<_, _> = (0..1000).map(|i| (i, i)).collect();
+        map.update_all(|k, v| *v += k + 1);
+        assert_eq!(map.len(), 1000);
+        for i in 0..1000 {
+            assert_eq!(map.get(&i), Some(&(2 * i + 1)));
+        }
+    }
+
+    #[test]
+    fn test_retain() {
+        let mut map = Map::new();
+        for i in 0..100 {
+            map.insert(i, i);
+        }
+        map.retain(|_, v| *v % 2 == 0);
+        assert_eq!(map.len(), 50);
+        for i in 0..100 {
+            assert_eq!(map.get(&i), (i % 2 == 0).then_some(&i));
+        }
+    }
+
+    #[test]
+    fn test_get_key_value() {
+        let mut map = Map::new();
+        let key = "one".to_string();
+        let stored = key.as_ptr();
+        map.insert(key, 1);
+        let lookup = "one".to_string();
+        let (k, v) = map.get_key_value(&lookup).unwrap();
+        assert_eq!((k.as_str(), *v), ("one", 1));
+        assert_eq!(k.as_ptr(), stored);
+        assert_ne!(k.as_ptr(), lookup.as_ptr());
+        assert_eq!(map.get_key_value("two"), None);
+    }
+
+    #[test]
+    fn test_set() {
+        let mut set = Set::new();
+        assert!(set.is_empty());
+        assert!(set.insert("a".to_string()));
+        assert!(set.insert("b".to_string()));
+        assert!(!set.insert("a".to_string()));
+        assert_eq!(set.len(), 2);
+        assert!(set.contains("a"));
+        assert!(!set.contains("c"));
+
+        assert!(set.remove("a"));
+        assert!(!set.remove("a"));
+        assert!(!set.contains("a"));
+        assert_eq!(set.iter().collect::<Vec<_>>(), ["b"]);
+        assert_eq!(set.into_iter().collect::<Vec<_>>(), ["b"]);
+    }
+
+    #[test]
+    fn test_set_subset_and_disjoint() {
+        let small: Set<i32> = (0..5).collect();
+        let large: Set<i32> = (0..100).collect();
+        let other: Set<i32> = (100..200).collect();
+
+        assert!(small.is_subset(&large));
+        assert!(!large.is_subset(&small));
+        assert!(small.is_subset(&small));
+        assert!(Set::new().is_subset(&small));
+
+        assert!(large.is_disjoint(&other));
+        assert!(other.is_disjoint(&small));
+        assert!(!small.is_disjoint(&large));
+        assert!(!large.is_disjoint(&small));
+    }
+
+    #[test]
+    fn test_remove_entry() {
+        let mut map = Map::new();
+        map.insert("one".to_string(), 1);
+        map.insert("two".to_string(), 2);
+        assert_eq!(map.remove_entry("one"), Some(("one".to_string(), 1)));
+        assert_eq!(map.count, 1);
+        assert_eq!(map.remove_entry("one"), None);
+        assert_eq!(map.get("two"), Some(&2));
+    }
+
+    #[test]
+    fn test_churn_keeps_empty_slots() {
+        let mut map = Map::new();
+        for i in 0..10 {
+            map.insert(i, i);
+        }
+        for i in 10..50_000 {
+            map.insert(i, i);
+            assert_eq!(map.delete(&i), Some(i));
+        }
+        assert_eq!(map.slots.len(), INITIAL_SIZE);
+        assert!(map.slots.iter().any(|s| matches!(s, Slot::Empty)));
+        assert_eq!(map.len(), 10);
+        for i in 0..10 {
+            assert_eq!(map.get(&i), Some(&i));
+        }
+        assert_eq!(map.get(&49_999), None);
+        assert_eq!(map.slots.iter().filter(|s| s.is_occupied()).count(), 10);
+    }
+
+    #[test]
+    fn test_delete_from_middle_of_chain() {
+        let mut map = Map::new();
+        let len = map.slots.len();
+        let home = len - 3;
+        // 链从表尾绕回表头，中间再混入理想位置在后面的键
+        let chain = keys_with_home(&map, home, 6);
+        let later = keys_with_home(&map, 1, 2);
+        for &k in chain.iter().chain(&later) {
+            map.insert(k, k);
+        }
+
+        assert_eq!(map.delete(&chain[1]), Some(chain[1]));
+        assert_eq!(map.delete(&chain[4]), Some(chain[4]));
+        assert_eq!(map.len(), 6);
+        for &k in chain.iter().chain(&later) {
+            let expected = (k != chain[1] && k != chain[4]).then_some(&k);
+            assert_eq!(map.get(&k), expected);
+        }
+        for (i, slot) in map.slots.iter().enumerate() {
+            if let Slot::Occupied(entry) = slot {
+                let prev = (i + len - 1) % len;
+                assert!(map.hash(&entry.key) == i || map.slots[prev].is_occupied());
+            }
+        }
+    }
+
+    #[test]
+    fn test_retain_across_wrap() {
+        let mut map = Map::new();
+        let len = map.slots.len();
+        let chain = keys_with_home(&map, len - 2, 5);
+        for &k in &chain {
+            map.insert(k, k);
+        }
+        let mut calls = 0;
+        map.retain(|k, _| {
+            calls += 1;
+            *k != chain[0] && *k != chain[3]
+        });
+        assert_eq!(calls, 5);
+        assert_eq!(map.len(), 3);
+        for &k in &chain {
+            assert_eq!(map.contains_key(&k), k != chain[0] && k != chain[3]);
+        }
+    }
+
+    #[test]
+    fn test_hash_mask_matches_modulo() {
+        let mut map = Map::new();
+        for i in 0..1000 {
+            let len = map.slots.len();
+            assert_eq!(map.hash(&i), map.hasher.hash_one(i) as usize % len);
+            map.insert(i, i);
+        }
+        assert!(map.slots.len() > INITIAL_SIZE);
+    }
+
+    #[test]
+    fn test_probe_stats() {
+        let mut map = Map::new();
+        assert_eq!(map.probe_stats().max, 0);
+        // 20 个键挤在同一个理想位置上，探查距离依次为 0..20
+        for k in keys_with_home(&map, 5, 20) {
+            map.insert(k, k);
+        }
+        let stats = map.probe_stats();
+        assert_eq!(stats.max, 19);
+        assert_eq!(stats.p99, 19);
+        assert_eq!(stats.average, 9.5);
+        assert_eq!(stats.load_factor, 20.0 / INITIAL_SIZE as f64);
+    }
+
+    #[test]
+    fn test_resize_does_not_rehash() {
+        use std::cell::Cell;
+
+        use crate::fixtures::HashCounter;
+
+        let hashes = Cell::new(0);
+        let mut map = Map::new();
+        for id in 0..1000 {
+            map.insert(
+                HashCounter {
+                    id,
+                    hashes: &hashes,
+                },
+                id,
+            );
+        }
+        assert!(map.slots.len() > INITIAL_SIZE);
+        assert_eq!(hashes.get(), 1000);
+        let probe = HashCounter {
+            id: 7,
+            hashes: &hashes,
+        };
+        assert_eq!(map.get(&probe), Some(&7));
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn test_serde_round_trip() {
+        let map: Map<String, i32> = (0..100).map(|i| (format!("key{}", i), i)).collect();
+        let json = serde_json::to_string(&map).unwrap();
+        let back: Map<String, i32> = serde_json::from_str(&json).unwrap();
+        assert_eq!(back, map);
+
+        let value: serde_json::Value = serde_json::from_str(&json).unwrap();
+        assert_eq!(value["key42"], 42);
+        assert_eq!(value.as_object().unwrap().len(), 100);
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn test_serde_duplicate_keys() {
+        let map: Map<String, i32> = serde_json::from_str(r#"{"a": 1, "b": 2, "a": 3}"#).unwrap();
+        assert_eq!(map.len(), 2);
+        assert_eq!(map.get("a"), Some(&3));
+        assert_eq!(map.get("b"), Some(&2));
+        assert!(serde_json::from_str::<Map<String, i32>>("[1, 2]").is_err());
+    }
 }
@@ -0,0 +1,308 @@
+use std::{
+    borrow::Borrow,
+    hash::{BuildHasher, Hash, RandomState},
+    iter, mem,
+};
+
+/// A linear-probing map that keeps probe runs sorted by distance from home.
+/// An incoming entry that has travelled farther than a resident takes its
+/// slot, which evens out probe lengths and lets lookups for missing keys stop
+/// early.
+pub struct Map<K: Hash + Eq, V, S = RandomState> {
+    slots: Vec<Option<Bucket<K, V>>>,
+    count: usize,
+    hasher: S,
+}
+
+struct Bucket<K, V> {
+    key: K,
+    value: V,
+    /// How many slots the entry sits past its home slot.
+    dist: usize,
+}
+
+const INITIAL_SIZE: usize = 64;
+const LOAD_FACTOR: f64 = 0.9;
+const EXPANSION_FACTOR: usize = 2;
+
+impl<K: Hash + Eq, V> Map<K, V> {
+    pub fn new() -> Self {
+        Self::with_hasher(RandomState::new())
+    }
+}
+
+impl<K: Hash + Eq, V, S: BuildHasher> Map<K, V, S> {
+    /// Creates a map that hashes its keys with `hasher`.
+    pub fn with_hasher(hasher: S) -> Self {
+        Map {
+            slots: iter::repeat_with(|| None).take(INITIAL_SIZE).collect(),
+            count: 0,
+            hasher,
+        }
+    }
+
+    pub fn insert(&mut self, key: K, value: V) -> Option<V> {
+        if let Some(i) = self.find_index(&key) {
+            return Some(mem::replace(
+                &mut self.slots[i].as_mut().unwrap().value,
+                value,
+            ));
+        }
+        self.expand();
+        self.place(Bucket {
+            key,
+            value,
+            dist: 0,
+        });
+        None
+    }
+
+    pub fn get<Q>(&self, key: &Q) -> Option<&V>
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+    {
+        let i = self.find_index(key)?;
+        Some(&self.slots[i].as_ref().unwrap().value)
+    }
+
+    pub fn get_mut<Q>(&mut self, key: &Q) -> Option<&mut V>
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+    {
+        let i = self.find_index(key)?;
+        Some(&mut self.slots[i].as_mut().unwrap().value)
+    }
+
+    pub fn contains_key<Q>(&self, key: &Q) -> bool
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+    {
+        self.find_index(key).is_some()
+    }
+
+    /// Removes `key` and shifts the rest of its probe run back by one, so
+    /// no tombstone is needed.
+    pub fn delete<Q>(&mut self, key: &Q) -> Option<V>
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+    {
+        let i = self.find_index(key)?;
+        let entry = self.slots[i].take().unwrap();
+        self.count -= 1;
+        let mask = self.slots.len() - 1;
+        let mut gap = i;
+        let mut j = (i + 1) & mask;
+        while self.slots[j].as_ref().is_some_and(|next| next.dist > 0) {
+            let mut next = self.slots[j].take().unwrap();
+            next.dist -= 1;
+            self.slots[gap] = Some(next);
+            gap = j;
+            j = (j + 1) & mask;
+        }
+        Some(entry.value)
+    }
+
+    pub fn len(&self) -> usize {
+        self.count
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.count == 0
+    }
+
+    pub fn iter(&self) -> Iter<'_, K, V> {
+        Iter {
+            slots: &self.slots,
+            i: 0,
+        }
+    }
+
+    /// Returns how many entries sit each distance away from their home slot,
+    /// indexed by distance.
+    pub fn probe_histogram(&self) -> Vec<usize> {
+        let mut histogram = Vec::new();
+        for entry in self.slots.iter().flatten() {
+            if histogram.len() <= entry.dist {
+                histogram.resize(entry.dist + 1, 0);
+            }
+            histogram[entry.dist] += 1;
+        }
+        histogram
+    }
+
+    /// Puts an entry known to be absent into the table, displacing residents
+    /// that are closer to home than it is.
+    fn place(&mut self, mut incoming: Bucket<K, V>) {
+        let mask = self.slots.len() - 1;
+        let mut i = (self.hash(&incoming.key) + incoming.dist) & mask;
+        loop {
+            match &mut self.slots[i] {
+                Some(resident) => {
+                    // 劫富济贫：离家更近的常驻元素让出槽位，继续向后找位置
+                    if resident.dist < incoming.dist {
+                        mem::swap(resident, &mut incoming);
+                    }
+                }
+                slot @ None => {
+                    *slot = Some(incoming);
+                    self.count += 1;
+                    return;
+                }
+            }
+            incoming.dist += 1;
+            i = (i + 1) & mask;
+        }
+    }
+
+    fn find_index<Q>(&self, key: &Q) -> Option<usize>
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+    {
+        let mask = self.slots.len() - 1;
+        let mut i = self.hash(key);
+        let mut dist = 0;
+        loop {
+            match &self.slots[i] {
+                // 常驻元素离家比我们更近，说明要找的键不可能在更后面
+                Some(entry) if entry.dist >= dist => {
+                    if entry.key.borrow() == key {
+                        return Some(i);
+                    }
+                }
+                _ => return None,
+            }
+            dist += 1;
+            i = (i + 1) & mask;
+        }
+    }
+
+    fn expand(&mut self) {
+        if ((self.count as f64) / (self.slots.len() as f64)) < LOAD_FACTOR {
+            return;
+        }
+        let new_slots = iter::repeat_with(|| None)
+            .take(self.slots.len() * EXPANSION_FACTOR)
+            .collect();
+        let old_slots = mem::replace(&mut self.slots, new_slots);
+        self.count = 0;
+        for mut entry in old_slots.into_iter().flatten() {
+            entry.dist = 0;
+            self.place(entry);
+        }
+    }
+
+    fn hash<Q>(&self, key: &Q) -> usize
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+    {
+        debug_assert!(self.slots.len().is_power_of_two());
+        self.hasher.hash_one(key) as usize & (self.slots.len() - 1)
+    }
+}
+
+impl<K: Hash + Eq, V, S: BuildHasher + Default> Default for Map<K, V, S> {
+    fn default() -> Self {
+        Self::with_hasher(S::default())
+    }
+}
+
+pub struct Iter<'a, K: Hash + Eq, V> {
+    slots: &'a [Option<Bucket<K, V>>],
+    i: usize,
+}
+
+impl<'a, K: Hash + Eq, V> Iterator for Iter<'a, K, V> {
+    type Item = (&'a K, &'a V);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        while self.i < self.slots.len() {
+            if let Some(entry) = &self.slots[self.i] {
+                self.i += 1;
+                return Some((&entry.key, &entry.value));
+            }
+            self.i += 1;
+        }
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::open_addressing;
+    use std::{
+        collections::HashMap as StdHashMap,
+        hash::{BuildHasherDefault, DefaultHasher},
+    };
+
+    type Fixed = BuildHasherDefault<DefaultHasher>;
+
+    fn stats(histogram: &[usize]) -> (f64, usize) {
+        let total: usize = histogram.iter().sum();
+        let sum: usize = histogram.iter().enumerate().map(|(d, n)| d * n).sum();
+        (sum as f64 / total as f64, histogram.len() - 1)
+    }
+
+    #[test]
+    fn test_insert_get_delete() {
+        let mut map = Map::new();
+        assert_eq!(map.insert("one".to_string(), 1), None);
+        assert_eq!(map.insert("two".to_string(), 2), None);
+        assert_eq!(map.insert("one".to_string(), 11), Some(1));
+        assert_eq!(map.len(), 2);
+        assert_eq!(map.get("one"), Some(&11));
+
+        *map.get_mut("two").unwrap() += 1;
+        assert_eq!(map.delete("two"), Some(3));
+        assert_eq!(map.delete("two"), None);
+        assert!(!map.contains_key("two"));
+        assert_eq!(map.len(), 1);
+    }
+
+    #[test]
+    fn test_against_std() {
+        let mut map = Map::new();
+        let mut std_map = StdHashMap::new();
+        for i in 0..5000 {
+            map.insert(i, i);
+            std_map.insert(i, i);
+        }
+        for i in (0..5000).step_by(3) {
+            assert_eq!(map.delete(&i), std_map.remove(&i));
+        }
+        assert_eq!(map.len(), std_map.len());
+        assert_eq!(map.iter().count(), std_map.len());
+        for i in 0..5000 {
+            assert_eq!(map.get(&i), std_map.get(&i));
+        }
+        let mask = map.slots.len() - 1;
+        for entry in map.slots.iter().flatten() {
+            let slot_index = (map.hash(&entry.key) + entry.dist) & mask;
+            assert_eq!(map.find_index(&entry.key), Some(slot_index));
+        }
+    }
+
+    #[test]
+    fn test_probe_lengths_against_linear() {
+        // 两边用同一个哈希器和同样的数据，槽位数也一致
+        let mut robin_hood = Map::with_hasher(Fixed::default());
+        let mut linear = open_addressing::Map::with_hasher(Fixed::default());
+        for i in 0..7000 {
+            robin_hood.insert(i, i);
+            linear.insert(i, i);
+        }
+        let (rh_avg, rh_max) = stats(&robin_hood.probe_histogram());
+        let (linear_avg, linear_max) = stats(&linear.probe_histogram());
+
+        // 线性探查下总位移与冲突处理顺序无关，所以平均值相同，只有最大值变小
+        assert!((rh_avg - linear_avg).abs() < 1e-9);
+        assert!(rh_max <= linear_max);
+        assert!(rh_max < linear_max / 2);
+    }
+}
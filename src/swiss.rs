@@ -1,555 +1,4396 @@
 use std::{
-    hash::{BuildHasher, Hash, RandomState},
-    iter, mem,
+    borrow::Borrow,
+    error::Error,
+    fmt::{self, Debug},
+    hash::{BuildHasher, BuildHasherDefault, DefaultHasher, Hash, RandomState},
+    iter::{self, FusedIterator},
+    mem::{self, MaybeUninit},
+    ops::Index,
 };
 
-pub struct Map<K: Hash + Eq, V> {
-    slots: Vec<Option<Entry<K, V>>>,
+use crate::cardinality::CardinalityEstimator;
+
+/// Keys, values and hashes live in parallel arrays, so probing compares keys
+/// without pulling values into cache. The control bytes are the only record
+/// of which slots are full: `keys` and `values` are initialized exactly there.
+///
+/// `G` is the number of control bytes per group, 8 or 16. Groups of 16 are
+/// compared with one SSE2 or NEON instruction; other widths and targets use
+/// SWAR. `new` builds a 16-wide map; the other constructors, `Default` and
+/// `collect` build whatever width the annotated type names.
+pub struct Map<K: Hash + Eq, V, S = RandomState, const G: usize = GROUP_SIZE> {
+    keys: Vec<MaybeUninit<K>>,
+    values: Vec<MaybeUninit<V>>,
+    /// The full hash of each key, kept so that resizing need not rehash it.
+    hashes: Vec<u64>,
     count: usize,
+    deleted: usize,
     group_count: usize,
-    ctrl: Vec<Ctrl>,
-    hasher: RandomState,
+    ctrl: Vec<Ctrl<G>>,
+    hasher: S,
+    /// The load factor the map was configured with. The one in effect can be
+    /// lower for large tables; see `max_load_factor`.
+    base_load_factor: f64,
+    adaptive_load_factor: bool,
+    peak: usize,
+    /// Bumped whenever an entry is removed or the table is rebuilt, so that
+    /// `get_at` can tell that a `SlotRef` no longer points at its entry.
+    generation: usize,
+    #[cfg(any(test, feature = "debug-internals"))]
+    resize_count: usize,
 }
 
-struct Entry<K, V> {
+/// An entry on its way into or out of the table.
+struct Bucket<K, V> {
     key: K,
     value: V,
+    hash: u64,
+}
+
+/// A single change applied by `Map::apply_updates`.
+pub enum Update<V> {
+    Set(V),
+    Delete,
+    Modify(Box<dyn FnOnce(&mut V)>),
+}
+
+/// A cached position of an entry, usable with `get_at` to skip probing. It
+/// goes stale once the table is rehashed or any entry is removed, after which
+/// `get_at` returns `None` for it.
+#[derive(Copy, Clone, Debug)]
+pub struct SlotRef {
+    slot_index: usize,
+    slot_count: usize,
+    generation: usize,
 }
 
+/// The control bytes of one group.
 #[derive(Copy, Clone)]
-struct Ctrl(u64);
+struct Ctrl<const G: usize>([u8; G]);
 
 enum Slot {
     Deleted,
     Occupied(u8),
 }
 
-const GROUP_SIZE: usize = 8;
+const GROUP_SIZE: usize = 16;
+const INITIAL_GROUP_COUNT: usize = 4;
+const LOAD_FACTOR: f64 = 0.9;
+const EXPANSION_FACTOR: usize = 2;
 
 impl<K: Hash + Eq, V> Map<K, V> {
     pub fn new() -> Self {
-        const INITIAL_GROUP_COUNT: usize = 8;
-        const INITIAL_SIZE: usize = INITIAL_GROUP_COUNT * GROUP_SIZE;
-        Self {
-            slots: iter::repeat_with(|| None).take(INITIAL_SIZE).collect(),
-            count: 0,
-            group_count: INITIAL_GROUP_COUNT,
-            ctrl: vec![Ctrl::new(); INITIAL_GROUP_COUNT],
-            hasher: RandomState::new(),
+        Self::with_hasher(RandomState::new())
+    }
+
+    /// Builds a map from `pairs`, inserting them ordered by home group so that
+    /// writes sweep the table front to back instead of landing at random.
+    pub fn build_bucketed(pairs: Vec<(K, V)>) -> Self {
+        let mut map = Self::with_group_count(
+            Self::group_count_for(pairs.len(), LOAD_FACTOR),
+            RandomState::new(),
+        );
+        let mut hashed: Vec<_> = pairs
+            .into_iter()
+            .map(|(key, value)| (map.hasher.hash_one(&key), key, value))
+            .collect();
+        // A stable sort keeps duplicate keys in order, so the last one wins.
+        hashed.sort_by_key(|&(hash, _, _)| map.split_hash(hash).0);
+        for (hash, key, value) in hashed {
+            map.insert_hashed(key, value, hash);
+        }
+        map
+    }
+
+    /// Estimates how many distinct keys `keys` yields without storing them,
+    /// so that a map can be sized once before loading a large stream.
+    pub fn estimate_capacity<'a>(keys: impl Iterator<Item = &'a K>) -> usize
+    where
+        K: 'a,
+    {
+        let hasher = RandomState::new();
+        let mut estimator = CardinalityEstimator::new();
+        for key in keys {
+            estimator.insert_hash(hasher.hash_one(key));
         }
+        estimator.estimate()
+    }
+}
+
+impl<K: Hash + Eq, V, const G: usize> Map<K, V, RandomState, G> {
+    /// Creates a map that holds at least `cap` entries before growing.
+    pub fn with_capacity(cap: usize) -> Self {
+        Self::with_capacity_and_hasher(cap, RandomState::new())
+    }
+}
+
+impl<K: Hash + Eq, V, S: BuildHasher, const G: usize> Map<K, V, S, G> {
+    /// Creates a map that hashes its keys with `hasher`.
+    pub fn with_hasher(hasher: S) -> Self {
+        Self::with_group_count(INITIAL_GROUP_COUNT, hasher)
+    }
+
+    /// Creates a map that holds at least `cap` entries before growing and
+    /// hashes its keys with `hasher`.
+    pub fn with_capacity_and_hasher(cap: usize, hasher: S) -> Self {
+        Self::with_group_count(Self::group_count_for(cap, LOAD_FACTOR), hasher)
     }
+}
 
+impl<K: Hash + Eq, V, S: BuildHasher, const G: usize> Map<K, V, S, G> {
     pub fn insert(&mut self, key: K, value: V) -> Option<V> {
         if self.is_overloaded() {
-            self.expand();
+            self.make_room();
         }
-        let (group_index, h2) = self.hash(&key);
-        if let Some(slot_index) = self.find_slot_index(&key, group_index, h2) {
-            return Some(mem::replace(
-                &mut self.slots[slot_index].as_mut().unwrap().value,
-                value,
-            ));
+        let hash = self.hasher.hash_one(&key);
+        self.insert_hashed(key, value, hash)
+    }
+
+    /// Like `entry`, but takes the key by reference and only turns it into an
+    /// owned key if a value is inserted, so hits never allocate.
+    pub fn entry_ref<'b, Q>(&mut self, key: &'b Q) -> EntryRef<'_, 'b, K, Q, V, S, G>
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq + ToOwned<Owned = K> + ?Sized,
+    {
+        let hash = self.hasher.hash_one(key);
+        let (group_index, h2) = self.split_hash(hash);
+        match self.find_slot_index(key, group_index, h2) {
+            Some(slot_index) => EntryRef::Occupied(OccupiedEntry {
+                map: self,
+                slot_index,
+            }),
+            None => EntryRef::Vacant(VacantEntryRef {
+                map: self,
+                key,
+                hash,
+            }),
+        }
+    }
+
+    /// Inserts `value` only if `key` is absent. Otherwise the map is left
+    /// as is and the error hands back `value` along with the existing entry.
+    pub fn try_insert(
+        &mut self,
+        key: K,
+        value: V,
+    ) -> Result<&mut V, OccupiedError<'_, K, V, S, G>> {
+        match self.entry(key) {
+            Entry::Occupied(entry) => Err(OccupiedError { entry, value }),
+            Entry::Vacant(entry) => Ok(entry.insert(value)),
+        }
+    }
+
+    /// Inserts like `insert` and returns the stored value, so it can be
+    /// changed without looking the key up again.
+    pub fn insert_and_get(&mut self, key: K, value: V) -> &mut V {
+        match self.entry(key) {
+            Entry::Occupied(mut entry) => {
+                entry.insert(value);
+                entry.into_mut()
+            }
+            Entry::Vacant(entry) => entry.insert(value),
+        }
+    }
+
+    pub fn entry(&mut self, key: K) -> Entry<'_, K, V, S, G> {
+        let hash = self.hasher.hash_one(&key);
+        let (group_index, h2) = self.split_hash(hash);
+        match self.find_slot_index(&key, group_index, h2) {
+            Some(slot_index) => Entry::Occupied(OccupiedEntry {
+                map: self,
+                slot_index,
+            }),
+            None => Entry::Vacant(VacantEntry {
+                map: self,
+                key,
+                hash,
+            }),
         }
-        let slot_index = self.find_empty_slot_index(group_index);
-        let (group_index, ctrl_index) = self.get_group_and_ctrl_indices(slot_index);
-        self.ctrl[group_index].set(ctrl_index, Slot::Occupied(h2));
-        self.count += 1;
-        self.slots[slot_index] = Some(Entry { key, value });
-        None
     }
 
-    pub fn get(&self, key: &K) -> Option<&V> {
+    /// Returns a builder for looking entries up by a precomputed hash and an
+    /// equality closure instead of by key.
+    pub fn raw_entry_mut(&mut self) -> RawEntryBuilderMut<'_, K, V, S, G> {
+        RawEntryBuilderMut { map: self }
+    }
+
+    pub fn get<Q>(&self, key: &Q) -> Option<&V>
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+    {
         let (group_index, h2) = self.hash(key);
         let slot_index = self.find_slot_index(key, group_index, h2)?;
-        Some(&self.slots[slot_index].as_ref().unwrap().value)
+        Some(self.value_at(slot_index))
     }
 
-    pub fn get_mut(&mut self, key: &K) -> Option<&mut V> {
+    /// Returns the stored key along with its value.
+    pub fn get_key_value<Q>(&self, key: &Q) -> Option<(&K, &V)>
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+    {
         let (group_index, h2) = self.hash(key);
         let slot_index = self.find_slot_index(key, group_index, h2)?;
-        Some(&mut self.slots[slot_index].as_mut().unwrap().value)
+        Some((self.key_at(slot_index), self.value_at(slot_index)))
     }
 
-    pub fn contains(&self, key: &K) -> bool {
+    pub fn get_mut<Q>(&mut self, key: &Q) -> Option<&mut V>
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+    {
         let (group_index, h2) = self.hash(key);
-        self.find_slot_index(key, group_index, h2).is_some()
+        let slot_index = self.find_slot_index(key, group_index, h2)?;
+        Some(self.value_at_mut(slot_index))
+    }
+
+    /// Returns mutable references to the values of several keys at once.
+    ///
+    /// # Panics
+    ///
+    /// Panics if any two of `keys` are equal.
+    pub fn get_disjoint_mut<Q, const N: usize>(&mut self, keys: [&Q; N]) -> [Option<&mut V>; N]
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+    {
+        for (i, key) in keys.iter().enumerate() {
+            assert!(
+                !keys[..i].contains(key),
+                "duplicate keys passed to get_disjoint_mut"
+            );
+        }
+        let slot_indices = keys.map(|key| {
+            let (group_index, h2) = self.hash(key);
+            self.find_slot_index(key, group_index, h2)
+        });
+        let values = self.values.as_mut_ptr();
+        // Distinct keys live in distinct slots, so the references never alias.
+        slot_indices
+            .map(|slot_index| slot_index.map(|i| unsafe { (*values.add(i)).assume_init_mut() }))
+    }
+
+    /// Returns the value for `key`, inserting `f()` first if it is missing.
+    /// The key is hashed and looked up once either way.
+    pub fn get_or_insert_with<F: FnOnce() -> V>(&mut self, key: K, f: F) -> &mut V {
+        self.entry(key).or_insert_with(f)
+    }
+
+    /// Returns the value for `key`, inserting `f()` first if it is missing.
+    /// The key is only cloned when it has to be stored.
+    pub fn get_or_insert_ref<F: FnOnce() -> V>(&mut self, key: &K, f: F) -> &mut V
+    where
+        K: Clone,
+    {
+        let hash = self.hasher.hash_one(key);
+        let (group_index, h2) = self.split_hash(hash);
+        let slot_index = match self.find_slot_index(key, group_index, h2) {
+            Some(slot_index) => slot_index,
+            None => self.insert_new(key.clone(), f(), hash),
+        };
+        self.value_at_mut(slot_index)
     }
 
-    pub fn delete(&mut self, key: &K) -> Option<V> {
+    pub fn contains_key<Q>(&self, key: &Q) -> bool
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+    {
         let (group_index, h2) = self.hash(key);
-        let slot_index = self.find_slot_index(key, group_index, h2)?;
-        let (group_index, ctrl_index) = self.get_group_and_ctrl_indices(slot_index);
-        self.ctrl[group_index].set(ctrl_index, Slot::Deleted);
-        self.count -= 1;
-        Some(self.slots[slot_index].take().unwrap().value)
+        self.find_slot_index(key, group_index, h2).is_some()
     }
 
-    pub fn iter(&self) -> Iter<'_, K, V> {
-        Iter { map: self, i: 0 }
+    pub fn contains<Q>(&self, key: &Q) -> bool
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+    {
+        self.contains_key(key)
     }
 
-    pub fn iter_mut(&mut self) -> IterMut<'_, K, V> {
-        IterMut { map: self, i: 0 }
+    pub fn delete<Q>(&mut self, key: &Q) -> Option<V>
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+    {
+        self.remove_entry(key).map(|(_, value)| value)
     }
 
-    fn find_slot_index(&self, key: &K, group_index: usize, h2: u8) -> Option<usize> {
-        let mut i = group_index;
-        loop {
-            let ctrl = &self.ctrl[i];
-            let (mut matches, found_empty) = ctrl.find_h2(h2);
-            while matches != 0 {
-                let ctrl_index = matches.trailing_zeros() as usize;
-                let slot_index = self.get_slot_index(i, ctrl_index);
-                if let Some(entry) = &self.slots[slot_index] {
-                    if entry.key == *key {
-                        return Some(slot_index);
-                    }
+    /// Like `delete`, but also hands back the stored key.
+    pub fn remove_entry<Q>(&mut self, key: &Q) -> Option<(K, V)>
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+    {
+        let (group_index, h2) = self.hash(key);
+        let slot_index = self.find_slot_index(key, group_index, h2)?;
+        let entry = self.remove_at(slot_index);
+        Some((entry.key, entry.value))
+    }
+
+    /// Keeps only the entries for which `f` returns true, marking the slots
+    /// of the others as deleted.
+    pub fn retain<F: FnMut(&K, &mut V) -> bool>(&mut self, mut f: F) {
+        for slot_index in 0..self.slot_count() {
+            if is_full(&self.ctrl, slot_index) {
+                let (key, value) = self.entry_at_mut(slot_index);
+                if !f(key, value) {
+                    self.remove_at(slot_index);
                 }
-                matches &= matches - 1;
-            }
-            i = (i + 1) % self.group_count;
-            if found_empty || i == group_index {
-                return None;
             }
         }
     }
 
-    fn find_empty_slot_index(&self, group_index: usize) -> usize {
-        let mut i = group_index;
-        loop {
-            if let Some(ctrl_index) = self.ctrl[i].find_empty_and_deleted() {
-                return self.get_slot_index(i, ctrl_index);
-            }
-            i = (i + 1) % self.group_count;
-            if i == group_index {
-                unreachable!(
-                    "The map should always have empty slots because we expand when overloaded."
-                );
+    /// Applies `f` to every entry in one pass over the table.
+    pub fn update_all<F: FnMut(&K, &mut V)>(&mut self, mut f: F) {
+        for slot_index in 0..self.slot_count() {
+            if is_full(&self.ctrl, slot_index) {
+                let (key, value) = self.entry_at_mut(slot_index);
+                f(key, value);
             }
         }
     }
 
-    const fn get_slot_index(&self, group_index: usize, ctrl_index: usize) -> usize {
-        group_index * GROUP_SIZE + ctrl_index
+    pub fn slot_ref(&self, key: &K) -> Option<SlotRef> {
+        let (group_index, h2) = self.hash(key);
+        let slot_index = self.find_slot_index(key, group_index, h2)?;
+        Some(SlotRef {
+            slot_index,
+            slot_count: self.slot_count(),
+            generation: self.generation,
+        })
     }
 
-    const fn get_group_and_ctrl_indices(&self, slot_index: usize) -> (usize, usize) {
-        (slot_index / GROUP_SIZE, slot_index % GROUP_SIZE)
+    pub fn get_at(&self, handle: &SlotRef) -> Option<(&K, &V)> {
+        if handle.generation != self.generation
+            || handle.slot_count != self.slot_count()
+            || !is_full(&self.ctrl, handle.slot_index)
+        {
+            return None;
+        }
+        Some((
+            self.key_at(handle.slot_index),
+            self.value_at(handle.slot_index),
+        ))
     }
 
-    const fn is_overloaded(&self) -> bool {
-        self.count as f64 / self.slots.len() as f64 >= 0.9
+    /// Returns roughly how many heap bytes the table takes: the key, value
+    /// and hash arrays plus the control bytes. Memory owned by the keys and
+    /// values themselves is not counted.
+    pub fn memory_footprint(&self) -> usize {
+        self.slot_count() * (mem::size_of::<K>() + mem::size_of::<V>() + mem::size_of::<u64>())
+            + self.ctrl.len() * mem::size_of::<Ctrl<G>>()
     }
 
-    fn expand(&mut self) {
-        const EXPANSION_FACTOR: usize = 2;
-        let new_group_count = self.group_count * EXPANSION_FACTOR;
-        let new_size = new_group_count * GROUP_SIZE;
-        let mut new_map = Self {
-            slots: iter::repeat_with(|| None).take(new_size).collect(),
-            count: 0,
-            group_count: new_group_count,
-            ctrl: vec![Ctrl::new(); new_group_count],
-            hasher: RandomState::new(),
-        };
-        for entry in mem::take(&mut self.slots).into_iter() {
-            if let Some(entry) = entry {
-                new_map.insert(entry.key, entry.value);
-            }
+    /// Returns the bytes allocated for the table per byte of live key-value
+    /// data, or infinity for an empty map.
+    pub fn overhead_ratio(&self) -> f64 {
+        self.memory_footprint() as f64 / (self.count * mem::size_of::<(K, V)>()) as f64
+    }
+
+    /// Grows the table straight to the smallest power-of-two size of at least
+    /// `min_slots` in a single rehash. Does nothing if it is already that big.
+    pub fn grow_to_at_least(&mut self, min_slots: usize) {
+        let group_count = min_slots.next_power_of_two().div_ceil(G);
+        if group_count > self.group_count {
+            self.resize(group_count);
         }
-        *self = new_map;
     }
 
-    fn hash(&self, key: &K) -> (usize, u8) {
-        let h = self.hasher.hash_one(key);
-        const H2_LEN: usize = 7;
-        const H2_MASK: u8 = 0b0111_1111;
-        let h1 = h >> H2_LEN;
-        let h2 = h as u8 & H2_MASK;
-        let group_index = (h1 % self.group_count as u64) as usize;
-        (group_index, h2)
+    /// Grows the table as a normal expansion would, but migrates the entries
+    /// `batch` at a time and calls `progress(done, total)` after each batch so
+    /// that a long rehash can be reported on. The map is complete on return.
+    pub fn rehash_incremental(&mut self, batch: usize, mut progress: impl FnMut(usize, usize)) {
+        assert!(batch > 0, "batch size must be positive");
+        let total = self.count;
+        let mut old = self.replace_table(self.group_count * EXPANSION_FACTOR);
+        let mut entries = iter::from_fn(|| old.next_entry());
+        let mut done = 0;
+        while done < total {
+            for entry in entries.by_ref().take(batch) {
+                self.place(entry);
+                done += 1;
+            }
+            progress(done, total);
+        }
     }
-}
 
-impl Ctrl {
-    const SLOT_EMPTY: u8 = 0b1000_0000;
-    const SLOT_DELETED: u8 = 0b1111_1110;
+    /// Returns the fraction of slots holding live entries. Deleted slots are
+    /// not counted; see `tombstone_ratio`.
+    pub fn load_factor(&self) -> f64 {
+        self.count as f64 / self.slot_count() as f64
+    }
 
-    const fn new() -> Self {
-        Self(u64::from_ne_bytes([Self::SLOT_EMPTY; 8]))
+    /// Returns the fraction of slots marked deleted. These count towards the
+    /// load that triggers growth, and a rehash clears them.
+    pub fn tombstone_ratio(&self) -> f64 {
+        self.deleted as f64 / self.slot_count() as f64
     }
 
-    fn find_h2(self, h2: u8) -> (u8, bool) {
-        #[cfg(all(target_arch = "x86_64", target_feature = "sse2"))]
-        unsafe {
-            (self.match_byte(h2), self.match_byte(Self::SLOT_EMPTY) != 0)
-        }
+    /// Returns how many entries the map can hold before it next grows.
+    pub fn capacity(&self) -> usize {
+        (self.slot_count() as f64 * self.max_load_factor()) as usize
+    }
 
-        #[cfg(not(all(target_arch = "x86_64", target_feature = "sse2")))]
+    /// Grows the table so that `additional` more entries fit without
+    /// resizing. Does nothing if there is already room.
+    pub fn reserve(&mut self, additional: usize) {
+        let group_count = self.group_count_to_hold(self.count + additional);
+        if group_count > self.group_count {
+            self.resize(group_count);
+        } else if self.group_count_to_hold(self.count + self.deleted + additional)
+            > self.group_count
         {
-            let mut matches = 0;
-            for i in 0..GROUP_SIZE {
-                let c = (self.0 >> (i * 8)) as u8;
-                if c == Self::SLOT_EMPTY {
-                    return (matches, true);
-                }
-                if c == h2 {
-                    matches |= 1 << i;
-                }
-            }
-            (matches, false)
+            // There is room, but deleted slots would use it up first, so
+            // rehash in place to turn them back into empty ones.
+            self.resize(self.group_count);
         }
     }
 
-    fn find_empty_and_deleted(self) -> Option<usize> {
-        #[cfg(all(target_arch = "x86_64", target_feature = "sse2"))]
-        unsafe {
-            let matches = self.match_empty_or_deleted();
-            if matches != 0 {
-                Some(matches.trailing_zeros() as usize)
-            } else {
-                None
-            }
+    /// Rebuilds the table at the smallest size that holds the live entries,
+    /// dropping every deleted slot along the way.
+    pub fn shrink_to_fit(&mut self) {
+        self.resize(self.group_count_to_hold(self.count));
+    }
+
+    /// Grows the table to hold `peak` entries and keeps that room through
+    /// `auto_shrink` until the live count falls well below the peak again.
+    pub fn reserve_peak(&mut self, peak: usize) {
+        self.peak = peak;
+        self.reserve(peak.saturating_sub(self.count));
+    }
+
+    /// Shrinks the table if it is at least four times larger than the live
+    /// entries, or the peak recorded by `reserve_peak`, need. Returns whether
+    /// the table was shrunk.
+    pub fn auto_shrink(&mut self) -> bool {
+        const SHRINK_FACTOR: usize = 4;
+        if self.count < self.peak / SHRINK_FACTOR {
+            self.peak = 0;
         }
+        let group_count = self.group_count_to_hold(self.count.max(self.peak));
+        if group_count * SHRINK_FACTOR > self.group_count {
+            return false;
+        }
+        self.resize(group_count);
+        true
+    }
 
-        #[cfg(not(all(target_arch = "x86_64", target_feature = "sse2")))]
-        {
-            for i in 0..GROUP_SIZE {
-                let c = (self.0 >> (i * 8)) as u8;
-                if c == Self::SLOT_EMPTY || c == Self::SLOT_DELETED {
-                    return Some(i);
-                }
+    /// Recomputes the tag of every full slot from its stored hash. Which
+    /// slots are full, empty or deleted is left as is, since the control
+    /// bytes are the only record of it.
+    #[cfg(feature = "debug-internals")]
+    pub fn rebuild_ctrl(&mut self) {
+        for slot_index in 0..self.slot_count() {
+            if is_full(&self.ctrl, slot_index) {
+                let (_, h2) = self.split_hash(self.hashes[slot_index]);
+                let (group_index, ctrl_index) = self.get_group_and_ctrl_indices(slot_index);
+                self.ctrl[group_index].set(ctrl_index, Slot::Occupied(h2));
             }
-            None
         }
     }
 
-    fn set(&mut self, i: usize, slot: Slot) {
-        let c = match slot {
-            Slot::Deleted => Self::SLOT_DELETED,
-            Slot::Occupied(h2) => h2,
-        };
-        let clear_mask = !((0xff as u64) << (i * 8));
-        self.0 &= clear_mask;
-        self.0 |= (c as u64) << (i * 8);
+    /// Lowers the load factor of large tables to keep their probe chains
+    /// short. Disabled by default.
+    pub fn set_adaptive_load_factor(&mut self, enabled: bool) {
+        self.adaptive_load_factor = enabled;
     }
 
-    #[cfg(all(target_arch = "x86_64", target_feature = "sse2"))]
-    #[inline]
-    #[target_feature(enable = "sse2")]
-    unsafe fn match_byte(self, byte: u8) -> u8 {
-        use std::arch::x86_64::*;
-        unsafe {
-            let targets = _mm_set1_epi8(byte as i8);
-            let controls = _mm_loadl_epi64(&self.0 as *const u64 as *const __m128i);
-            let cmp = _mm_cmpeq_epi8(controls, targets);
-            _mm_movemask_epi8(cmp) as u8
-        }
+    /// Returns how many times the table has been rebuilt at a new size.
+    #[cfg(any(test, feature = "debug-internals"))]
+    pub fn resize_count(&self) -> usize {
+        self.resize_count
     }
 
-    #[cfg(all(target_arch = "x86_64", target_feature = "sse2"))]
-    #[inline]
-    #[target_feature(enable = "sse2")]
-    unsafe fn match_empty_or_deleted(self) -> u8 {
-        use std::arch::x86_64::*;
-        unsafe {
-            let controls = _mm_loadl_epi64(&self.0 as *const u64 as *const __m128i);
-            _mm_movemask_epi8(controls) as u8
-        }
+    /// Checks that `handle` still points at the entry for `key`.
+    pub fn validate_handle(&self, handle: &SlotRef, key: &K) -> bool {
+        self.get_at(handle).is_some_and(|(k, _)| k == key)
     }
-}
 
-pub struct Iter<'a, K: Hash + Eq, V> {
-    map: &'a Map<K, V>,
-    i: usize,
-}
-
-impl<'a, K: Hash + Eq, V> Iterator for Iter<'a, K, V> {
-    type Item = (&'a K, &'a V);
+    /// Returns the number of slots in the smallest table that would hold the
+    /// live entries without growing, ignoring any deleted slots.
+    pub fn ideal_capacity(&self) -> usize {
+        self.group_count_to_hold(self.count) * G
+    }
 
-    fn next(&mut self) -> Option<Self::Item> {
-        while self.i < self.map.slots.len() {
-            if let Some(entry) = &self.map.slots[self.i] {
-                self.i += 1;
-                return Some((&entry.key, &entry.value));
-            }
-            self.i += 1;
-        }
-        None
+    /// Returns how many keys are in exactly one of `self` and `other`.
+    pub fn key_symmetric_difference_len(&self, other: &Self) -> usize {
+        let (small, large) = if self.count <= other.count {
+            (self, other)
+        } else {
+            (other, self)
+        };
+        let common = small.iter().filter(|(k, _)| large.contains_key(k)).count();
+        self.count + other.count - 2 * common
     }
-}
 
-pub struct IterMut<'a, K: Hash + Eq, V> {
-    map: &'a mut Map<K, V>,
-    i: usize,
-}
+    /// Moves the entries of `other` into `self`, keeping the larger value on
+    /// key collisions, so that the result does not depend on merge direction.
+    pub fn merge_deterministic(&mut self, other: Self)
+    where
+        V: Ord,
+    {
+        self.merge_by(other, |new, old| new > old);
+    }
 
-impl<'a, K: Hash + Eq, V> Iterator for IterMut<'a, K, V> {
-    type Item = (&'a K, &'a mut V);
+    /// Like `merge_deterministic`, but keeps the smaller value.
+    pub fn merge_deterministic_min(&mut self, other: Self)
+    where
+        V: Ord,
+    {
+        self.merge_by(other, |new, old| new < old);
+    }
 
-    fn next(&mut self) -> Option<Self::Item> {
-        while self.i < self.map.slots.len() {
-            if let Some(entry) = &mut self.map.slots[self.i] {
-                self.i += 1;
-                let entry: *mut Entry<K, V> = entry;
-                return Some(unsafe { (&(*entry).key, &mut (*entry).value) });
+    fn merge_by(&mut self, other: Self, replace: impl Fn(&V, &V) -> bool) {
+        for (key, value) in other {
+            match self.get_mut(&key) {
+                Some(old) if replace(&value, old) => *old = value,
+                Some(_) => {}
+                None => {
+                    self.insert(key, value);
+                }
             }
-            self.i += 1;
         }
-        None
     }
-}
-
-pub struct IntoIter<K: Hash + Eq, V> {
-    map: Map<K, V>,
-    i: usize,
-}
 
-impl<K: Hash + Eq, V> Iterator for IntoIter<K, V> {
-    type Item = (K, V);
+    /// Returns a fingerprint of the key set that ignores values and order.
+    /// Keys are hashed with a fixed seed, so maps holding the same keys agree.
+    pub fn key_fingerprint(&self) -> u64 {
+        let hasher = BuildHasherDefault::<DefaultHasher>::default();
+        self.iter()
+            .fold(0, |acc, (k, _)| acc.wrapping_add(hasher.hash_one(k)))
+    }
 
-    fn next(&mut self) -> Option<Self::Item> {
-        while self.i < self.map.slots.len() {
-            if let Some(entry) = self.map.slots[self.i].take() {
-                self.i += 1;
-                return Some((entry.key, entry.value));
+    /// Applies a batch of updates in order, resolving each key once. `Delete`
+    /// and `Modify` do nothing for missing keys.
+    pub fn apply_updates<U: IntoIterator<Item = (K, Update<V>)>>(&mut self, updates: U) {
+        for (key, update) in updates {
+            match update {
+                Update::Set(value) => {
+                    self.insert(key, value);
+                }
+                Update::Delete => {
+                    self.delete(&key);
+                }
+                Update::Modify(f) => {
+                    if let Some(value) = self.get_mut(&key) {
+                        f(value);
+                    }
+                }
             }
-            self.i += 1;
         }
-        None
     }
-}
 
-impl<K: Hash + Eq, V> IntoIterator for Map<K, V> {
-    type Item = (K, V);
-    type IntoIter = IntoIter<K, V>;
+    pub fn iter(&self) -> Iter<'_, K, V, G> {
+        Iter {
+            ctrl: &self.ctrl,
+            keys: &self.keys,
+            values: &self.values,
+            i: 0,
+            end: self.slot_count(),
+            remaining: self.count,
+        }
+    }
 
-    fn into_iter(self) -> Self::IntoIter {
-        IntoIter { map: self, i: 0 }
+    pub fn iter_mut(&mut self) -> IterMut<'_, K, V, G> {
+        IterMut {
+            ctrl: &self.ctrl,
+            keys: &self.keys,
+            values: &mut self.values,
+            i: 0,
+            remaining: self.count,
+        }
     }
-}
 
-#[cfg(test)]
-mod tests {
-    use std::collections::HashMap as StdHashMap;
+    pub fn hasher(&self) -> &S {
+        &self.hasher
+    }
 
-    use super::*;
+    pub fn len(&self) -> usize {
+        self.count
+    }
 
-    #[test]
-    fn test_insert_and_get() {
-        let mut map = Map::new();
-        map.insert("key1".to_string(), 1);
-        map.insert("key2".to_string(), 2);
+    pub fn is_empty(&self) -> bool {
+        self.count == 0
+    }
 
-        assert_eq!(map.get(&"key1".to_string()), Some(&1));
-        assert_eq!(map.get(&"key2".to_string()), Some(&2));
-        assert_eq!(map.get(&"key3".to_string()), None);
+    pub fn keys(&self) -> Keys<'_, K, V, G> {
+        Keys { inner: self.iter() }
     }
 
-    #[test]
-    fn test_insert_overwrite() {
-        let mut map = Map::new();
-        map.insert("key1".to_string(), 1);
-        assert_eq!(map.get(&"key1".to_string()), Some(&1));
+    pub fn values(&self) -> Values<'_, K, V, G> {
+        Values { inner: self.iter() }
+    }
 
-        let old_value = map.insert("key1".to_string(), 2);
-        assert_eq!(old_value, Some(1));
-        assert_eq!(map.get(&"key1".to_string()), Some(&2));
+    pub fn values_mut(&mut self) -> ValuesMut<'_, K, V, G> {
+        ValuesMut {
+            inner: self.iter_mut(),
+        }
     }
 
-    #[test]
-    fn test_get_mut() {
-        let mut map = Map::new();
-        map.insert("key1".to_string(), 1);
+    /// Consumes the map, yielding its keys and dropping the values.
+    pub fn into_keys(self) -> IntoKeys<K, V, G> {
+        IntoKeys {
+            inner: self.into_iter(),
+        }
+    }
 
-        let value = map.get_mut(&"key1".to_string()).unwrap();
-        *value = 10;
+    /// Consumes the map, yielding its values and dropping the keys.
+    pub fn into_values(self) -> IntoValues<K, V, G> {
+        IntoValues {
+            inner: self.into_iter(),
+        }
+    }
 
-        assert_eq!(map.get(&"key1".to_string()), Some(&10));
+    /// Removes every entry, yielding them by value, and keeps the allocated
+    /// table. The map is empty as soon as this returns; entries the iterator
+    /// does not get to are dropped along with it.
+    pub fn drain(&mut self) -> Drain<'_, K, V, G> {
+        // The iterator takes the old control bytes with it, so the map never
+        // claims slots whose entries have already been moved out.
+        let ctrl = mem::replace(&mut self.ctrl, vec![Ctrl::new(); self.group_count]);
+        let remaining = mem::take(&mut self.count);
+        self.deleted = 0;
+        self.generation = self.generation.wrapping_add(1);
+        Drain {
+            ctrl,
+            keys: &mut self.keys,
+            values: &mut self.values,
+            i: 0,
+            remaining,
+        }
     }
 
-    #[test]
-    fn test_delete() {
-        let mut map = Map::new();
-        map.insert("key1".to_string(), 1);
-        map.insert("key2".to_string(), 2);
+    /// Removes all entries, keeping the allocated table. Deleted slots are
+    /// reset to empty along with the full ones.
+    pub fn clear(&mut self) {
+        /// Empties every group when dropped, even while unwinding from a
+        /// panicking key or value drop, so that the entries not dropped yet
+        /// are leaked instead of staying marked full.
+        struct EmptyOnDrop<'a, const G: usize>(&'a mut [Ctrl<G>]);
 
-        assert_eq!(map.delete(&"key1".to_string()), Some(1));
-        assert_eq!(map.get(&"key1".to_string()), None);
-        assert_eq!(map.get(&"key2".to_string()), Some(&2));
-        assert_eq!(map.delete(&"key3".to_string()), None);
-    }
+        impl<const G: usize> Drop for EmptyOnDrop<'_, G> {
+            fn drop(&mut self) {
+                self.0.fill(Ctrl::new());
+            }
+        }
 
-    #[test]
-    fn test_expansion() {
-        let mut map = Map::new();
-        for i in 0..100 {
-            map.insert(i.to_string(), i);
+        self.count = 0;
+        self.deleted = 0;
+        self.generation = self.generation.wrapping_add(1);
+        let ctrl = EmptyOnDrop(&mut self.ctrl);
+        if mem::needs_drop::<K>() || mem::needs_drop::<V>() {
+            for (group_index, group) in ctrl.0.iter().enumerate() {
+                for ctrl_index in 0..G {
+                    if group.is_full(ctrl_index) {
+                        let slot_index = group_index * G + ctrl_index;
+                        unsafe {
+                            self.keys[slot_index].assume_init_drop();
+                            self.values[slot_index].assume_init_drop();
+                        }
+                    }
+                }
+            }
         }
+    }
 
-        assert!(map.slots.len() > 64); // Check that expansion happened
-        for i in 0..100 {
-            assert_eq!(map.get(&i.to_string()), Some(&i));
+    /// Moves every entry out of `other` into this map, leaving `other` empty.
+    /// Values from `other` win where both maps hold a key.
+    pub fn append(&mut self, other: &mut Self) {
+        self.reserve(other.len());
+        for (key, value) in other.drain() {
+            self.insert(key, value);
         }
     }
 
-    #[test]
-    fn test_delete_and_reinsert() {
-        let mut map = Map::new();
-        map.insert("key1".to_string(), 1);
-        assert_eq!(map.delete(&"key1".to_string()), Some(1));
-        assert_eq!(map.get(&"key1".to_string()), None);
-        map.insert("key1".to_string(), 2);
-        assert_eq!(map.get(&"key1".to_string()), Some(&2));
+    /// Returns a cursor over the entries that can remove the one it points at
+    /// without disturbing the rest of the traversal.
+    pub fn cursor_mut(&mut self) -> CursorMut<'_, K, V, S, G> {
+        let mut cursor = CursorMut { map: self, i: 0 };
+        cursor.seek();
+        cursor
+    }
+
+    /// Iterates over the entries whose key starts with `prefix`. This scans
+    /// the whole table, so it is O(capacity) regardless of how many match.
+    pub fn iter_prefix<'a>(&'a self, prefix: &'a str) -> impl Iterator<Item = (&'a K, &'a V)>
+    where
+        K: Borrow<str>,
+    {
+        self.iter()
+            .filter(move |(k, _)| (*k).borrow().starts_with(prefix))
+    }
+
+    fn insert_hashed(&mut self, key: K, value: V, hash: u64) -> Option<V> {
+        let (group_index, h2) = self.split_hash(hash);
+        if let Some(slot_index) = self.find_slot_index(&key, group_index, h2) {
+            return Some(mem::replace(self.value_at_mut(slot_index), value));
+        }
+        self.place(Bucket { key, value, hash });
+        None
+    }
+
+    /// Inserts a key known to be absent, growing the table first if needed,
+    /// and returns the slot it landed in.
+    fn insert_new(&mut self, key: K, value: V, hash: u64) -> usize {
+        if self.is_overloaded() {
+            self.make_room();
+        }
+        self.place(Bucket { key, value, hash })
+    }
+
+    /// Puts an entry known to be absent into the first free slot of its
+    /// probe sequence, using the hash stored in it.
+    fn place(&mut self, entry: Bucket<K, V>) -> usize {
+        let (group_index, h2) = self.split_hash(entry.hash);
+        let slot_index = self.find_empty_slot_index(group_index);
+        let (group_index, ctrl_index) = self.get_group_and_ctrl_indices(slot_index);
+        if self.ctrl[group_index].get(ctrl_index) == Ctrl::<G>::SLOT_DELETED {
+            self.deleted -= 1;
+        }
+        self.ctrl[group_index].set(ctrl_index, Slot::Occupied(h2));
+        self.count += 1;
+        self.keys[slot_index].write(entry.key);
+        self.values[slot_index].write(entry.value);
+        self.hashes[slot_index] = entry.hash;
+        slot_index
+    }
+
+    fn remove_at(&mut self, slot_index: usize) -> Bucket<K, V> {
+        debug_assert!(is_full(&self.ctrl, slot_index));
+        let (group_index, ctrl_index) = self.get_group_and_ctrl_indices(slot_index);
+        self.ctrl[group_index].set(ctrl_index, Slot::Deleted);
+        self.count -= 1;
+        self.deleted += 1;
+        self.generation = self.generation.wrapping_add(1);
+        // The slot is no longer marked full, so nothing reads or drops these
+        // again.
+        unsafe {
+            Bucket {
+                key: self.keys[slot_index].assume_init_read(),
+                value: self.values[slot_index].assume_init_read(),
+                hash: self.hashes[slot_index],
+            }
+        }
+    }
+
+    // The accessors below must only be given full slots.
+
+    fn key_at(&self, slot_index: usize) -> &K {
+        debug_assert!(is_full(&self.ctrl, slot_index));
+        unsafe { self.keys[slot_index].assume_init_ref() }
+    }
+
+    fn value_at(&self, slot_index: usize) -> &V {
+        debug_assert!(is_full(&self.ctrl, slot_index));
+        unsafe { self.values[slot_index].assume_init_ref() }
+    }
+
+    fn value_at_mut(&mut self, slot_index: usize) -> &mut V {
+        debug_assert!(is_full(&self.ctrl, slot_index));
+        unsafe { self.values[slot_index].assume_init_mut() }
+    }
+
+    fn entry_at_mut(&mut self, slot_index: usize) -> (&K, &mut V) {
+        debug_assert!(is_full(&self.ctrl, slot_index));
+        unsafe {
+            (
+                self.keys[slot_index].assume_init_ref(),
+                self.values[slot_index].assume_init_mut(),
+            )
+        }
+    }
+
+    fn find_slot_index<Q>(&self, key: &Q, group_index: usize, h2: u8) -> Option<usize>
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+    {
+        self.find_slot_index_by(group_index, h2, |k| k.borrow() == key)
+    }
+
+    /// Probes from `group_index` for a full slot tagged `h2` whose key
+    /// satisfies `is_match`.
+    fn find_slot_index_by(
+        &self,
+        group_index: usize,
+        h2: u8,
+        mut is_match: impl FnMut(&K) -> bool,
+    ) -> Option<usize> {
+        let mut i = group_index;
+        loop {
+            let next = (i + 1) & (self.group_count - 1);
+            // Start loading the next group while this one is compared, so a
+            // long probe chain does not stall on every group.
+            prefetch(&self.ctrl[next]);
+            let ctrl = &self.ctrl[i];
+            let (mut matches, found_empty) = ctrl.find_h2(h2);
+            while matches != 0 {
+                let ctrl_index = matches.trailing_zeros() as usize;
+                let slot_index = self.get_slot_index(i, ctrl_index);
+                // A tag match means the slot is full.
+                if is_match(self.key_at(slot_index)) {
+                    return Some(slot_index);
+                }
+                matches &= matches - 1;
+            }
+            i = next;
+            if found_empty || i == group_index {
+                return None;
+            }
+        }
+    }
+
+    fn find_empty_slot_index(&self, group_index: usize) -> usize {
+        let mut i = group_index;
+        loop {
+            if let Some(ctrl_index) = self.ctrl[i].find_empty_and_deleted() {
+                return self.get_slot_index(i, ctrl_index);
+            }
+            i = (i + 1) & (self.group_count - 1);
+            if i == group_index {
+                unreachable!(
+                    "The map should always have empty slots because we expand when overloaded."
+                );
+            }
+        }
+    }
+
+    const fn slot_count(&self) -> usize {
+        self.group_count * G
+    }
+
+    const fn get_slot_index(&self, group_index: usize, ctrl_index: usize) -> usize {
+        group_index * G + ctrl_index
+    }
+
+    const fn get_group_and_ctrl_indices(&self, slot_index: usize) -> (usize, usize) {
+        (slot_index / G, slot_index % G)
+    }
+
+    /// Deleted slots count towards the load, since probes have to step over
+    /// them just like live entries.
+    const fn is_overloaded(&self) -> bool {
+        (self.count + self.deleted) as f64 / self.slot_count() as f64 >= self.max_load_factor()
+    }
+
+    /// Returns the load factor in effect at the current size.
+    const fn max_load_factor(&self) -> f64 {
+        self.load_factor_at(self.group_count)
+    }
+
+    /// Returns the load factor in effect for a table of `group_count` groups.
+    const fn load_factor_at(&self, group_count: usize) -> f64 {
+        const ADAPTIVE_THRESHOLD: usize = 1 << 16;
+        const ADAPTIVE_LOAD_FACTOR: f64 = 0.75;
+        if self.adaptive_load_factor && group_count * G >= ADAPTIVE_THRESHOLD {
+            ADAPTIVE_LOAD_FACTOR.min(self.base_load_factor)
+        } else {
+            self.base_load_factor
+        }
+    }
+
+    /// Frees up room in an overloaded table. If the live entries alone are
+    /// well under the limit, the load is mostly deleted slots, and rehashing
+    /// at the same size turns them back into empty ones.
+    fn make_room(&mut self) {
+        if (self.count as f64 / self.slot_count() as f64) < self.max_load_factor() / 2.0 {
+            self.resize(self.group_count);
+        } else {
+            self.expand();
+        }
+    }
+
+    fn expand(&mut self) {
+        self.resize(self.group_count * EXPANSION_FACTOR);
+    }
+
+    fn resize(&mut self, group_count: usize) {
+        let mut old = self.replace_table(group_count);
+        while let Some(entry) = old.next_entry() {
+            self.place(entry);
+        }
+    }
+
+    /// Swaps in an empty table of `group_count` groups and returns the old
+    /// entries. The hasher and settings stay, so the entries can go back in.
+    fn replace_table(&mut self, group_count: usize) -> IntoIter<K, V, G> {
+        let remaining = mem::take(&mut self.count);
+        self.group_count = group_count;
+        self.deleted = 0;
+        self.generation = self.generation.wrapping_add(1);
+        #[cfg(any(test, feature = "debug-internals"))]
+        {
+            self.resize_count += 1;
+        }
+        IntoIter {
+            ctrl: mem::replace(&mut self.ctrl, vec![Ctrl::new(); group_count]),
+            keys: mem::replace(&mut self.keys, empty_slots(group_count * G)),
+            values: mem::replace(&mut self.values, empty_slots(group_count * G)),
+            hashes: mem::replace(&mut self.hashes, vec![0; group_count * G]),
+            i: 0,
+            remaining,
+        }
+    }
+
+    fn with_group_count(group_count: usize, hasher: S) -> Self {
+        Self {
+            keys: empty_slots(group_count * G),
+            values: empty_slots(group_count * G),
+            hashes: vec![0; group_count * G],
+            count: 0,
+            deleted: 0,
+            group_count,
+            ctrl: vec![Ctrl::new(); group_count],
+            hasher,
+            base_load_factor: LOAD_FACTOR,
+            adaptive_load_factor: false,
+            peak: 0,
+            generation: 0,
+            #[cfg(any(test, feature = "debug-internals"))]
+            resize_count: 0,
+        }
+    }
+
+    /// Returns the smallest power-of-two group count, no smaller than the
+    /// initial one, that holds `cap` entries under `load_factor`.
+    fn group_count_for(cap: usize, load_factor: f64) -> usize {
+        let mut group_count = INITIAL_GROUP_COUNT;
+        while ((group_count * G) as f64 * load_factor) < cap as f64 {
+            group_count *= 2;
+        }
+        group_count
+    }
+
+    /// Like `group_count_for`, but under the load factor this map would have
+    /// at the resulting size.
+    fn group_count_to_hold(&self, cap: usize) -> usize {
+        let group_count = Self::group_count_for(cap, self.base_load_factor);
+        // Adaptive mode may lower the load factor at that size, which then
+        // calls for more groups; it stays lowered at any larger size.
+        Self::group_count_for(cap, self.load_factor_at(group_count))
+    }
+
+    fn hash<Q>(&self, key: &Q) -> (usize, u8)
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+    {
+        self.split_hash(self.hasher.hash_one(key))
+    }
+
+    /// Splits a hash into the home group index and the 7-bit tag stored in
+    /// the control bytes.
+    fn split_hash(&self, h: u64) -> (usize, u8) {
+        const H2_LEN: usize = 7;
+        const H2_MASK: u8 = 0b0111_1111;
+        let h1 = h >> H2_LEN;
+        let h2 = h as u8 & H2_MASK;
+        debug_assert!(self.group_count.is_power_of_two());
+        let group_index = (h1 & (self.group_count as u64 - 1)) as usize;
+        (group_index, h2)
+    }
+}
+
+impl<K: Hash + Eq, V, S, const G: usize> Drop for Map<K, V, S, G> {
+    fn drop(&mut self) {
+        if !mem::needs_drop::<K>() && !mem::needs_drop::<V>() {
+            return;
+        }
+        for slot_index in 0..self.keys.len() {
+            if is_full(&self.ctrl, slot_index) {
+                unsafe {
+                    self.keys[slot_index].assume_init_drop();
+                    self.values[slot_index].assume_init_drop();
+                }
+            }
+        }
+    }
+}
+
+impl<K: Hash + Eq, V, S: BuildHasher + Default, const G: usize> Default for Map<K, V, S, G> {
+    fn default() -> Self {
+        Self::with_group_count(INITIAL_GROUP_COUNT, S::default())
+    }
+}
+
+impl<K: Hash + Eq, V: PartialEq, S: BuildHasher, const G: usize> PartialEq for Map<K, V, S, G> {
+    fn eq(&self, other: &Self) -> bool {
+        self.count == other.count && self.iter().all(|(k, v)| other.get(k) == Some(v))
+    }
+}
+
+impl<K: Hash + Eq, V: Eq, S: BuildHasher, const G: usize> Eq for Map<K, V, S, G> {}
+
+impl<K: Hash + Eq, V, S: BuildHasher + Default, const G: usize> FromIterator<(K, V)>
+    for Map<K, V, S, G>
+{
+    fn from_iter<I: IntoIterator<Item = (K, V)>>(iter: I) -> Self {
+        let iter = iter.into_iter();
+        let mut map = Self::with_group_count(
+            Self::group_count_for(iter.size_hint().0, LOAD_FACTOR),
+            S::default(),
+        );
+        for (key, value) in iter {
+            map.insert(key, value);
+        }
+        map
+    }
+}
+
+/// Later pairs overwrite earlier ones with the same key.
+impl<K: Hash + Eq, V, const N: usize> From<[(K, V); N]> for Map<K, V> {
+    fn from(pairs: [(K, V); N]) -> Self {
+        pairs.into_iter().collect()
+    }
+}
+
+impl<K: Hash + Eq, V, S: BuildHasher, const G: usize> Extend<(K, V)> for Map<K, V, S, G> {
+    fn extend<I: IntoIterator<Item = (K, V)>>(&mut self, iter: I) {
+        let iter = iter.into_iter();
+        self.reserve(iter.size_hint().0);
+        for (key, value) in iter {
+            self.insert(key, value);
+        }
+    }
+}
+
+impl<'a, K: Hash + Eq + Copy, V: Copy, S: BuildHasher, const G: usize> Extend<(&'a K, &'a V)>
+    for Map<K, V, S, G>
+{
+    fn extend<I: IntoIterator<Item = (&'a K, &'a V)>>(&mut self, iter: I) {
+        self.extend(iter.into_iter().map(|(&k, &v)| (k, v)));
+    }
+}
+
+impl<K, Q, V, S, const G: usize> Index<&Q> for Map<K, V, S, G>
+where
+    K: Hash + Eq + Borrow<Q>,
+    Q: Hash + Eq + ?Sized,
+    S: BuildHasher,
+{
+    type Output = V;
+
+    fn index(&self, key: &Q) -> &V {
+        self.get(key).expect("key not present in map")
+    }
+}
+
+impl<K: Hash + Eq + Debug, V: Debug, S: BuildHasher, const G: usize> Debug for Map<K, V, S, G> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_map().entries(self.iter()).finish()
+    }
+}
+
+/// Builds a `Map` from pairs pushed one at a time, growing the table a chunk
+/// of entries at a time instead of doubling whenever the load factor is hit.
+pub struct MapBuilder<K: Hash + Eq, V, const G: usize = GROUP_SIZE> {
+    map: Map<K, V, RandomState, G>,
+    chunk_size: usize,
+}
+
+const DEFAULT_CHUNK_SIZE: usize = 1024;
+
+impl<K: Hash + Eq, V> MapBuilder<K, V> {
+    pub fn new() -> Self {
+        Self::with_chunk_size(DEFAULT_CHUNK_SIZE)
+    }
+}
+
+impl<K: Hash + Eq, V, const G: usize> MapBuilder<K, V, G> {
+    pub fn with_chunk_size(chunk_size: usize) -> Self {
+        assert!(chunk_size > 0, "chunk size must be positive");
+        Self {
+            map: Map::with_hasher(RandomState::new()),
+            chunk_size,
+        }
+    }
+
+    /// Sets the fraction of slots, in `(0.0, 1.0)`, that may fill before the
+    /// map grows. A lower factor spends memory to keep probe chains short.
+    pub fn with_load_factor(mut self, load_factor: f64) -> Self {
+        assert!(
+            load_factor > 0.0 && load_factor < 1.0,
+            "load factor must be between 0 and 1"
+        );
+        self.map.base_load_factor = load_factor;
+        self
+    }
+
+    pub fn push(&mut self, key: K, value: V) {
+        if self.map.group_count_to_hold(self.map.count + 1) > self.map.group_count {
+            self.map.reserve(self.chunk_size);
+        }
+        self.map.insert(key, value);
+    }
+
+    /// Returns the built map, trimmed to the size its entries need, since
+    /// the last chunk was reserved in full whether or not it got used.
+    pub fn finish(mut self) -> Map<K, V, RandomState, G> {
+        let group_count = self.map.group_count_to_hold(self.map.count);
+        if group_count < self.map.group_count {
+            self.map.resize(group_count);
+        }
+        self.map
+    }
+}
+
+impl<K: Hash + Eq, V, const G: usize> Default for MapBuilder<K, V, G> {
+    fn default() -> Self {
+        Self::with_chunk_size(DEFAULT_CHUNK_SIZE)
+    }
+}
+
+/// A hash set backed by a `Map` with `()` values, which take no space.
+pub struct Set<T: Hash + Eq, S = RandomState, const G: usize = GROUP_SIZE> {
+    map: Map<T, (), S, G>,
+}
+
+impl<T: Hash + Eq> Set<T> {
+    pub fn new() -> Self {
+        Self { map: Map::new() }
+    }
+}
+
+impl<T: Hash + Eq, S: BuildHasher, const G: usize> Set<T, S, G> {
+    /// Creates a set that hashes its values with `hasher`.
+    pub fn with_hasher(hasher: S) -> Self {
+        Self {
+            map: Map::with_hasher(hasher),
+        }
+    }
+
+    /// Adds `value`, returning false if it was already present.
+    pub fn insert(&mut self, value: T) -> bool {
+        self.map.insert(value, ()).is_none()
+    }
+
+    pub fn contains<Q>(&self, value: &Q) -> bool
+    where
+        T: Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+    {
+        self.map.contains_key(value)
+    }
+
+    /// Removes `value`, returning whether it was present.
+    pub fn remove<Q>(&mut self, value: &Q) -> bool
+    where
+        T: Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+    {
+        self.map.delete(value).is_some()
+    }
+
+    pub fn len(&self) -> usize {
+        self.map.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.map.is_empty()
+    }
+
+    pub fn iter(&self) -> Keys<'_, T, (), G> {
+        self.map.keys()
+    }
+
+    /// Iterates over the values in `self` or `other`, each once.
+    pub fn union<'a>(&'a self, other: &'a Self) -> impl Iterator<Item = &'a T> {
+        self.iter()
+            .chain(other.iter().filter(move |value| !self.contains(*value)))
+    }
+
+    /// Iterates over the values in both `self` and `other`.
+    pub fn intersection<'a>(&'a self, other: &'a Self) -> impl Iterator<Item = &'a T> {
+        self.iter().filter(move |value| other.contains(*value))
+    }
+
+    /// Iterates over the values in `self` but not in `other`.
+    pub fn difference<'a>(&'a self, other: &'a Self) -> impl Iterator<Item = &'a T> {
+        self.iter().filter(move |value| !other.contains(*value))
+    }
+}
+
+impl<T: Hash + Eq, S: BuildHasher + Default, const G: usize> Default for Set<T, S, G> {
+    fn default() -> Self {
+        Self {
+            map: Map::default(),
+        }
+    }
+}
+
+impl<T: Hash + Eq, S: BuildHasher + Default, const G: usize> FromIterator<T> for Set<T, S, G> {
+    fn from_iter<I: IntoIterator<Item = T>>(iter: I) -> Self {
+        Self {
+            map: iter.into_iter().map(|value| (value, ())).collect(),
+        }
+    }
+}
+
+impl<T: Hash + Eq, S: BuildHasher, const G: usize> Extend<T> for Set<T, S, G> {
+    fn extend<I: IntoIterator<Item = T>>(&mut self, iter: I) {
+        self.map.extend(iter.into_iter().map(|value| (value, ())));
+    }
+}
+
+impl<T: Hash + Eq, S, const G: usize> IntoIterator for Set<T, S, G> {
+    type Item = T;
+    type IntoIter = iter::Map<IntoIter<T, (), G>, fn((T, ())) -> T>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.map.into_iter().map(|(value, ())| value)
+    }
+}
+
+impl<'a, T: Hash + Eq, S: BuildHasher, const G: usize> IntoIterator for &'a Set<T, S, G> {
+    type Item = &'a T;
+    type IntoIter = Keys<'a, T, (), G>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.iter()
+    }
+}
+
+fn empty_slots<T>(slot_count: usize) -> Vec<MaybeUninit<T>> {
+    iter::repeat_with(MaybeUninit::uninit)
+        .take(slot_count)
+        .collect()
+}
+
+fn is_full<const G: usize>(ctrl: &[Ctrl<G>], slot_index: usize) -> bool {
+    ctrl[slot_index / G].is_full(slot_index % G)
+}
+
+/// Asks the CPU to start pulling `data` into cache. Elsewhere this does
+/// nothing.
+#[inline]
+fn prefetch<T>(data: &T) {
+    #[cfg(target_arch = "x86_64")]
+    unsafe {
+        use std::arch::x86_64::{_MM_HINT_T0, _mm_prefetch};
+        _mm_prefetch::<_MM_HINT_T0>((data as *const T).cast());
+    }
+    #[cfg(not(target_arch = "x86_64"))]
+    let _ = data;
+}
+
+impl<const G: usize> Ctrl<G> {
+    const SLOT_EMPTY: u8 = 0b1000_0000;
+    const SLOT_DELETED: u8 = 0b1111_1110;
+
+    const fn new() -> Self {
+        // Masks have one bit per lane in a u16, and SWAR works on whole
+        // eight-lane words.
+        const { assert!(G == 8 || G == 16, "group width must be 8 or 16") };
+        Self([Self::SLOT_EMPTY; G])
+    }
+
+    fn find_h2(self, h2: u8) -> (u16, bool) {
+        (self.match_byte(h2), self.match_byte(Self::SLOT_EMPTY) != 0)
+    }
+
+    fn find_empty_and_deleted(self) -> Option<usize> {
+        let matches = self.match_empty_or_deleted();
+        if matches != 0 {
+            Some(matches.trailing_zeros() as usize)
+        } else {
+            None
+        }
+    }
+
+    /// Returns a mask of the lanes equal to `byte`, using SIMD where the
+    /// target has it and the group fills a whole vector.
+    fn match_byte(self, byte: u8) -> u16 {
+        #[cfg(all(target_arch = "x86_64", target_feature = "sse2"))]
+        if G == 16 {
+            return unsafe { self.match_byte_sse2(byte) };
+        }
+
+        #[cfg(all(target_arch = "aarch64", target_feature = "neon"))]
+        if G == 16 {
+            return unsafe { self.match_byte_neon(byte) };
+        }
+
+        self.match_byte_swar(byte)
+    }
+
+    fn match_empty_or_deleted(self) -> u16 {
+        #[cfg(all(target_arch = "x86_64", target_feature = "sse2"))]
+        if G == 16 {
+            return unsafe { self.match_empty_or_deleted_sse2() };
+        }
+
+        #[cfg(all(target_arch = "aarch64", target_feature = "neon"))]
+        if G == 16 {
+            return unsafe { self.match_empty_or_deleted_neon() };
+        }
+
+        self.match_empty_or_deleted_swar()
+    }
+
+    /// Returns a mask of the lanes equal to `byte`, working on eight lanes
+    /// at once inside a `u64`. Unlike the classic `(x - 0x01..) & !x & 0x80..`
+    /// test, this one is exact: a match never flags the lanes above it.
+    fn match_byte_swar(self, byte: u8) -> u16 {
+        const LOW_BITS: u64 = 0x7f7f_7f7f_7f7f_7f7f;
+        self.map_words(|word| {
+            let x = word ^ (byte as u64 * 0x0101_0101_0101_0101);
+            // A lane's high bit ends up clear only if the lane was zero.
+            !(((x & LOW_BITS) + LOW_BITS) | x | LOW_BITS)
+        })
+    }
+
+    /// Empty and deleted are the only control bytes with the high bit set.
+    fn match_empty_or_deleted_swar(self) -> u16 {
+        self.map_words(|word| word & 0x8080_8080_8080_8080)
+    }
+
+    /// Runs `f` on each eight-lane word of the group and packs the high bit
+    /// of every lane of the results into one bit per lane.
+    fn map_words(self, f: impl Fn(u64) -> u64) -> u16 {
+        // The multiplier moves the bit of lane i to bit 56 + i without any
+        // two partial products overlapping.
+        let lane_mask =
+            |high_bits: u64| ((high_bits >> 7).wrapping_mul(0x0102_0408_1020_4080) >> 56) as u16;
+        self.0
+            .chunks_exact(8)
+            .enumerate()
+            .fold(0, |mask, (i, word)| {
+                let word = u64::from_le_bytes(word.try_into().unwrap());
+                mask | lane_mask(f(word)) << (8 * i)
+            })
+    }
+
+    /// Empty and deleted markers have the top bit set, and tags never do.
+    const fn is_full(self, i: usize) -> bool {
+        self.0[i] & Self::SLOT_EMPTY == 0
+    }
+
+    const fn get(self, i: usize) -> u8 {
+        self.0[i]
+    }
+
+    fn set(&mut self, i: usize, slot: Slot) {
+        self.0[i] = match slot {
+            Slot::Deleted => Self::SLOT_DELETED,
+            Slot::Occupied(h2) => h2,
+        };
+    }
+
+    // The SIMD versions read a full 16-byte vector, so they are only called
+    // when `G == 16`.
+
+    #[cfg(all(target_arch = "x86_64", target_feature = "sse2"))]
+    #[inline]
+    #[target_feature(enable = "sse2")]
+    unsafe fn match_byte_sse2(self, byte: u8) -> u16 {
+        use std::arch::x86_64::*;
+        unsafe {
+            let targets = _mm_set1_epi8(byte as i8);
+            let controls = _mm_loadu_si128(self.0.as_ptr() as *const __m128i);
+            let cmp = _mm_cmpeq_epi8(controls, targets);
+            _mm_movemask_epi8(cmp) as u16
+        }
+    }
+
+    #[cfg(all(target_arch = "x86_64", target_feature = "sse2"))]
+    #[inline]
+    #[target_feature(enable = "sse2")]
+    unsafe fn match_empty_or_deleted_sse2(self) -> u16 {
+        use std::arch::x86_64::*;
+        unsafe {
+            let controls = _mm_loadu_si128(self.0.as_ptr() as *const __m128i);
+            _mm_movemask_epi8(controls) as u16
+        }
+    }
+
+    #[cfg(all(target_arch = "aarch64", target_feature = "neon"))]
+    #[inline]
+    #[target_feature(enable = "neon")]
+    unsafe fn match_byte_neon(self, byte: u8) -> u16 {
+        use std::arch::aarch64::*;
+        unsafe {
+            let controls = vld1q_u8(self.0.as_ptr());
+            Self::movemask_neon(vceqq_u8(controls, vdupq_n_u8(byte)))
+        }
+    }
+
+    #[cfg(all(target_arch = "aarch64", target_feature = "neon"))]
+    #[inline]
+    #[target_feature(enable = "neon")]
+    unsafe fn match_empty_or_deleted_neon(self) -> u16 {
+        use std::arch::aarch64::*;
+        unsafe {
+            let controls = vld1q_u8(self.0.as_ptr());
+            Self::movemask_neon(vtstq_u8(controls, vdupq_n_u8(0x80)))
+        }
+    }
+
+    /// NEON has no movemask, so weight each all-ones lane by its bit within
+    /// its half and add the halves up horizontally.
+    #[cfg(all(target_arch = "aarch64", target_feature = "neon"))]
+    #[inline]
+    #[target_feature(enable = "neon")]
+    unsafe fn movemask_neon(lanes: std::arch::aarch64::uint8x16_t) -> u16 {
+        use std::arch::aarch64::*;
+        const BITS: [u8; 16] = [1, 2, 4, 8, 16, 32, 64, 128, 1, 2, 4, 8, 16, 32, 64, 128];
+        unsafe {
+            let bits = vandq_u8(lanes, vld1q_u8(BITS.as_ptr()));
+            let low = vaddv_u8(vget_low_u8(bits)) as u16;
+            let high = vaddv_u8(vget_high_u8(bits)) as u16;
+            low | high << 8
+        }
+    }
+}
+
+pub struct Iter<'a, K: Hash + Eq, V, const G: usize = GROUP_SIZE> {
+    ctrl: &'a [Ctrl<G>],
+    keys: &'a [MaybeUninit<K>],
+    values: &'a [MaybeUninit<V>],
+    /// The slots in `i..end` have not been visited from either side.
+    i: usize,
+    end: usize,
+    /// Full slots not yet yielded.
+    remaining: usize,
+}
+
+impl<'a, K: Hash + Eq, V, const G: usize> Iter<'a, K, V, G> {
+    fn entry(&mut self, i: usize) -> (&'a K, &'a V) {
+        self.remaining -= 1;
+        unsafe {
+            (
+                self.keys[i].assume_init_ref(),
+                self.values[i].assume_init_ref(),
+            )
+        }
+    }
+}
+
+impl<'a, K: Hash + Eq, V, const G: usize> Iterator for Iter<'a, K, V, G> {
+    type Item = (&'a K, &'a V);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        while self.i < self.end {
+            let i = self.i;
+            self.i += 1;
+            if is_full(self.ctrl, i) {
+                return Some(self.entry(i));
+            }
+        }
+        None
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        (self.remaining, Some(self.remaining))
+    }
+}
+
+impl<K: Hash + Eq, V, const G: usize> DoubleEndedIterator for Iter<'_, K, V, G> {
+    fn next_back(&mut self) -> Option<Self::Item> {
+        while self.end > self.i {
+            self.end -= 1;
+            if is_full(self.ctrl, self.end) {
+                return Some(self.entry(self.end));
+            }
+        }
+        None
+    }
+}
+
+impl<K: Hash + Eq, V, const G: usize> ExactSizeIterator for Iter<'_, K, V, G> {}
+
+impl<K: Hash + Eq, V, const G: usize> FusedIterator for Iter<'_, K, V, G> {}
+
+pub struct IterMut<'a, K: Hash + Eq, V, const G: usize = GROUP_SIZE> {
+    ctrl: &'a [Ctrl<G>],
+    keys: &'a [MaybeUninit<K>],
+    values: &'a mut [MaybeUninit<V>],
+    i: usize,
+    /// Full slots not yet yielded.
+    remaining: usize,
+}
+
+impl<'a, K: Hash + Eq, V, const G: usize> Iterator for IterMut<'a, K, V, G> {
+    type Item = (&'a K, &'a mut V);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        while self.i < self.keys.len() {
+            let i = self.i;
+            self.i += 1;
+            if is_full(self.ctrl, i) {
+                // Each slot is visited once, so the value references never
+                // alias.
+                let value = self.values[i].as_mut_ptr();
+                self.remaining -= 1;
+                return Some(unsafe { (self.keys[i].assume_init_ref(), &mut *value) });
+            }
+        }
+        None
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        (self.remaining, Some(self.remaining))
+    }
+}
+
+impl<K: Hash + Eq, V, const G: usize> ExactSizeIterator for IterMut<'_, K, V, G> {}
+
+impl<K: Hash + Eq, V, const G: usize> FusedIterator for IterMut<'_, K, V, G> {}
+
+pub struct Keys<'a, K: Hash + Eq, V, const G: usize = GROUP_SIZE> {
+    inner: Iter<'a, K, V, G>,
+}
+
+impl<'a, K: Hash + Eq, V, const G: usize> Iterator for Keys<'a, K, V, G> {
+    type Item = &'a K;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.inner.next().map(|(k, _)| k)
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        self.inner.size_hint()
+    }
+}
+
+impl<K: Hash + Eq, V, const G: usize> FusedIterator for Keys<'_, K, V, G> {}
+
+pub struct Values<'a, K: Hash + Eq, V, const G: usize = GROUP_SIZE> {
+    inner: Iter<'a, K, V, G>,
+}
+
+impl<'a, K: Hash + Eq, V, const G: usize> Iterator for Values<'a, K, V, G> {
+    type Item = &'a V;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.inner.next().map(|(_, v)| v)
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        self.inner.size_hint()
+    }
+}
+
+impl<K: Hash + Eq, V, const G: usize> FusedIterator for Values<'_, K, V, G> {}
+
+pub struct ValuesMut<'a, K: Hash + Eq, V, const G: usize = GROUP_SIZE> {
+    inner: IterMut<'a, K, V, G>,
+}
+
+impl<'a, K: Hash + Eq, V, const G: usize> Iterator for ValuesMut<'a, K, V, G> {
+    type Item = &'a mut V;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.inner.next().map(|(_, v)| v)
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        self.inner.size_hint()
+    }
+}
+
+impl<K: Hash + Eq, V, const G: usize> FusedIterator for ValuesMut<'_, K, V, G> {}
+
+pub struct Drain<'a, K: Hash + Eq, V, const G: usize = GROUP_SIZE> {
+    ctrl: Vec<Ctrl<G>>,
+    keys: &'a mut [MaybeUninit<K>],
+    values: &'a mut [MaybeUninit<V>],
+    i: usize,
+    remaining: usize,
+}
+
+impl<K: Hash + Eq, V, const G: usize> Iterator for Drain<'_, K, V, G> {
+    type Item = (K, V);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        while self.i < self.keys.len() {
+            let i = self.i;
+            self.i += 1;
+            if is_full(&self.ctrl, i) {
+                self.remaining -= 1;
+                return Some(unsafe {
+                    (
+                        self.keys[i].assume_init_read(),
+                        self.values[i].assume_init_read(),
+                    )
+                });
+            }
+        }
+        None
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        (self.remaining, Some(self.remaining))
+    }
+}
+
+impl<K: Hash + Eq, V, const G: usize> FusedIterator for Drain<'_, K, V, G> {}
+
+impl<K: Hash + Eq, V, const G: usize> Drop for Drain<'_, K, V, G> {
+    fn drop(&mut self) {
+        self.for_each(drop);
+    }
+}
+
+pub enum Entry<'a, K: Hash + Eq, V, S = RandomState, const G: usize = GROUP_SIZE> {
+    Occupied(OccupiedEntry<'a, K, V, S, G>),
+    Vacant(VacantEntry<'a, K, V, S, G>),
+}
+
+pub struct OccupiedEntry<'a, K: Hash + Eq, V, S = RandomState, const G: usize = GROUP_SIZE> {
+    map: &'a mut Map<K, V, S, G>,
+    slot_index: usize,
+}
+
+/// Holds the key and its hash. The table only grows once a value is
+/// inserted, so an entry that is looked at and dropped costs no resize.
+pub struct VacantEntry<'a, K: Hash + Eq, V, S = RandomState, const G: usize = GROUP_SIZE> {
+    map: &'a mut Map<K, V, S, G>,
+    key: K,
+    hash: u64,
+}
+
+impl<'a, K: Hash + Eq, V, S: BuildHasher, const G: usize> Entry<'a, K, V, S, G> {
+    pub fn or_insert(self, default: V) -> &'a mut V {
+        self.or_insert_with(|| default)
+    }
+
+    pub fn or_insert_with<F: FnOnce() -> V>(self, f: F) -> &'a mut V {
+        self.or_insert_with_key(|_| f())
+    }
+
+    /// Like `or_insert_with`, but hands `f` the key that is about to be
+    /// stored, so a value derived from it needs no copy of the key.
+    pub fn or_insert_with_key<F: FnOnce(&K) -> V>(self, f: F) -> &'a mut V {
+        match self {
+            Self::Occupied(entry) => entry.into_mut(),
+            Self::Vacant(entry) => {
+                let value = f(&entry.key);
+                entry.insert(value)
+            }
+        }
+    }
+
+    pub fn or_default(self) -> &'a mut V
+    where
+        V: Default,
+    {
+        self.or_insert_with(V::default)
+    }
+
+    pub fn and_modify<F: FnOnce(&mut V)>(mut self, f: F) -> Self {
+        if let Self::Occupied(entry) = &mut self {
+            f(entry.get_mut());
+        }
+        self
+    }
+
+    pub fn key(&self) -> &K {
+        match self {
+            Self::Occupied(entry) => entry.key(),
+            Self::Vacant(entry) => entry.key(),
+        }
+    }
+}
+
+impl<'a, K: Hash + Eq, V, S: BuildHasher, const G: usize> OccupiedEntry<'a, K, V, S, G> {
+    pub fn key(&self) -> &K {
+        self.map.key_at(self.slot_index)
+    }
+
+    pub fn get(&self) -> &V {
+        self.map.value_at(self.slot_index)
+    }
+
+    pub fn get_mut(&mut self) -> &mut V {
+        self.map.value_at_mut(self.slot_index)
+    }
+
+    pub fn into_mut(self) -> &'a mut V {
+        self.map.value_at_mut(self.slot_index)
+    }
+
+    pub fn insert(&mut self, value: V) -> V {
+        mem::replace(self.get_mut(), value)
+    }
+
+    pub fn remove(self) -> V {
+        self.map.remove_at(self.slot_index).value
+    }
+}
+
+impl<'a, K: Hash + Eq, V, S: BuildHasher, const G: usize> VacantEntry<'a, K, V, S, G> {
+    pub fn key(&self) -> &K {
+        &self.key
+    }
+
+    pub fn insert(self, value: V) -> &'a mut V {
+        let slot_index = self.map.insert_new(self.key, value, self.hash);
+        self.map.value_at_mut(slot_index)
+    }
+}
+
+/// An entry looked up by `Map::entry_ref`. Occupied entries are the same as
+/// those from `Map::entry`.
+pub enum EntryRef<'a, 'b, K, Q, V, S = RandomState, const G: usize = GROUP_SIZE>
+where
+    K: Hash + Eq,
+    Q: ?Sized,
+{
+    Occupied(OccupiedEntry<'a, K, V, S, G>),
+    Vacant(VacantEntryRef<'a, 'b, K, Q, V, S, G>),
+}
+
+pub struct VacantEntryRef<'a, 'b, K, Q, V, S = RandomState, const G: usize = GROUP_SIZE>
+where
+    K: Hash + Eq,
+    Q: ?Sized,
+{
+    map: &'a mut Map<K, V, S, G>,
+    key: &'b Q,
+    hash: u64,
+}
+
+impl<'a, K, Q, V, S, const G: usize> EntryRef<'a, '_, K, Q, V, S, G>
+where
+    K: Hash + Eq + Borrow<Q>,
+    Q: Hash + Eq + ToOwned<Owned = K> + ?Sized,
+    S: BuildHasher,
+{
+    pub fn or_insert(self, default: V) -> &'a mut V {
+        self.or_insert_with(|| default)
+    }
+
+    pub fn or_insert_with<F: FnOnce() -> V>(self, f: F) -> &'a mut V {
+        match self {
+            Self::Occupied(entry) => entry.into_mut(),
+            Self::Vacant(entry) => entry.insert(f()),
+        }
+    }
+
+    pub fn or_default(self) -> &'a mut V
+    where
+        V: Default,
+    {
+        self.or_insert_with(V::default)
+    }
+
+    pub fn and_modify<F: FnOnce(&mut V)>(mut self, f: F) -> Self {
+        if let Self::Occupied(entry) = &mut self {
+            f(entry.get_mut());
+        }
+        self
+    }
+
+    pub fn key(&self) -> &Q {
+        match self {
+            Self::Occupied(entry) => entry.key().borrow(),
+            Self::Vacant(entry) => entry.key,
+        }
+    }
+}
+
+impl<'a, K, Q, V, S, const G: usize> VacantEntryRef<'a, '_, K, Q, V, S, G>
+where
+    K: Hash + Eq + Borrow<Q>,
+    Q: Hash + Eq + ToOwned<Owned = K> + ?Sized,
+    S: BuildHasher,
+{
+    pub fn key(&self) -> &Q {
+        self.key
+    }
+
+    pub fn insert(self, value: V) -> &'a mut V {
+        let slot_index = self.map.insert_new(self.key.to_owned(), value, self.hash);
+        self.map.value_at_mut(slot_index)
+    }
+}
+
+/// Returned by `Map::try_insert` when the key is already present. The map is
+/// left unchanged, and `value` is the one that was not inserted.
+pub struct OccupiedError<'a, K: Hash + Eq, V, S = RandomState, const G: usize = GROUP_SIZE> {
+    pub entry: OccupiedEntry<'a, K, V, S, G>,
+    pub value: V,
+}
+
+impl<'a, K: Hash + Eq + Debug, V: Debug, S: BuildHasher, const G: usize> Debug
+    for OccupiedError<'a, K, V, S, G>
+{
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("OccupiedError")
+            .field("key", self.entry.key())
+            .field("old_value", self.entry.get())
+            .field("new_value", &self.value)
+            .finish()
+    }
+}
+
+impl<'a, K: Hash + Eq + Debug, V: Debug, S: BuildHasher, const G: usize> fmt::Display
+    for OccupiedError<'a, K, V, S, G>
+{
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "failed to insert {:?}, key {:?} already exists with value {:?}",
+            self.value,
+            self.entry.key(),
+            self.entry.get(),
+        )
+    }
+}
+
+impl<'a, K: Hash + Eq + Debug, V: Debug, S: BuildHasher, const G: usize> Error
+    for OccupiedError<'a, K, V, S, G>
+{
+}
+
+/// Created by `Map::raw_entry_mut`.
+pub struct RawEntryBuilderMut<'a, K: Hash + Eq, V, S = RandomState, const G: usize = GROUP_SIZE> {
+    map: &'a mut Map<K, V, S, G>,
+}
+
+pub enum RawEntryMut<'a, K: Hash + Eq, V, S = RandomState, const G: usize = GROUP_SIZE> {
+    Occupied(RawOccupiedEntryMut<'a, K, V, S, G>),
+    Vacant(RawVacantEntryMut<'a, K, V, S, G>),
+}
+
+pub struct RawOccupiedEntryMut<'a, K: Hash + Eq, V, S = RandomState, const G: usize = GROUP_SIZE> {
+    map: &'a mut Map<K, V, S, G>,
+    slot_index: usize,
+}
+
+/// Remembers the hash it was looked up with, which is stored with whatever
+/// key is inserted through it.
+pub struct RawVacantEntryMut<'a, K: Hash + Eq, V, S = RandomState, const G: usize = GROUP_SIZE> {
+    map: &'a mut Map<K, V, S, G>,
+    hash: u64,
+}
+
+impl<'a, K: Hash + Eq, V, S: BuildHasher, const G: usize> RawEntryBuilderMut<'a, K, V, S, G> {
+    /// Looks for the entry whose key satisfies `is_match`, probing where
+    /// `hash` places it. `hash` must be what the map's hasher gives for the
+    /// key, or the entry is looked for in the wrong place.
+    pub fn from_hash<F: FnMut(&K) -> bool>(
+        self,
+        hash: u64,
+        is_match: F,
+    ) -> RawEntryMut<'a, K, V, S, G> {
+        let (group_index, h2) = self.map.split_hash(hash);
+        match self.map.find_slot_index_by(group_index, h2, is_match) {
+            Some(slot_index) => RawEntryMut::Occupied(RawOccupiedEntryMut {
+                map: self.map,
+                slot_index,
+            }),
+            None => RawEntryMut::Vacant(RawVacantEntryMut {
+                map: self.map,
+                hash,
+            }),
+        }
+    }
+}
+
+impl<'a, K: Hash + Eq, V, S: BuildHasher, const G: usize> RawEntryMut<'a, K, V, S, G> {
+    pub fn or_insert(self, key: K, value: V) -> (&'a K, &'a mut V) {
+        self.or_insert_with(|| (key, value))
+    }
+
+    pub fn or_insert_with<F: FnOnce() -> (K, V)>(self, f: F) -> (&'a K, &'a mut V) {
+        match self {
+            Self::Occupied(entry) => entry.into_key_value(),
+            Self::Vacant(entry) => {
+                let (key, value) = f();
+                entry.insert(key, value)
+            }
+        }
+    }
+}
+
+impl<'a, K: Hash + Eq, V, S: BuildHasher, const G: usize> RawOccupiedEntryMut<'a, K, V, S, G> {
+    pub fn key(&self) -> &K {
+        self.map.key_at(self.slot_index)
+    }
+
+    pub fn get(&self) -> &V {
+        self.map.value_at(self.slot_index)
+    }
+
+    pub fn get_mut(&mut self) -> &mut V {
+        self.map.value_at_mut(self.slot_index)
+    }
+
+    pub fn into_mut(self) -> &'a mut V {
+        self.map.value_at_mut(self.slot_index)
+    }
+
+    pub fn into_key_value(self) -> (&'a K, &'a mut V) {
+        self.map.entry_at_mut(self.slot_index)
+    }
+
+    pub fn insert(&mut self, value: V) -> V {
+        mem::replace(self.get_mut(), value)
+    }
+
+    pub fn remove_entry(self) -> (K, V) {
+        let entry = self.map.remove_at(self.slot_index);
+        (entry.key, entry.value)
+    }
+}
+
+impl<'a, K: Hash + Eq, V, S: BuildHasher, const G: usize> RawVacantEntryMut<'a, K, V, S, G> {
+    /// Inserts `key` under the hash the entry was looked up with, which must
+    /// be the map's hash of `key`.
+    pub fn insert(self, key: K, value: V) -> (&'a K, &'a mut V) {
+        let slot_index = self.map.insert_new(key, value, self.hash);
+        self.map.entry_at_mut(slot_index)
+    }
+}
+
+pub struct CursorMut<'a, K: Hash + Eq, V, S = RandomState, const G: usize = GROUP_SIZE> {
+    map: &'a mut Map<K, V, S, G>,
+    i: usize,
+}
+
+impl<K: Hash + Eq, V, S: BuildHasher, const G: usize> CursorMut<'_, K, V, S, G> {
+    pub fn current(&mut self) -> Option<(&K, &mut V)> {
+        if self.i >= self.map.slot_count() {
+            return None;
+        }
+        Some(self.map.entry_at_mut(self.i))
+    }
+
+    /// Removes the current entry and moves on to the next one.
+    pub fn remove_current(&mut self) -> Option<(K, V)> {
+        if self.i >= self.map.slot_count() {
+            return None;
+        }
+        let entry = self.map.remove_at(self.i);
+        self.advance();
+        Some((entry.key, entry.value))
+    }
+
+    pub fn advance(&mut self) {
+        if self.i < self.map.slot_count() {
+            self.i += 1;
+            self.seek();
+        }
+    }
+
+    fn seek(&mut self) {
+        while self.i < self.map.slot_count() && !is_full(&self.map.ctrl, self.i) {
+            self.i += 1;
+        }
+    }
+}
+
+pub struct IntoIter<K: Hash + Eq, V, const G: usize = GROUP_SIZE> {
+    ctrl: Vec<Ctrl<G>>,
+    keys: Vec<MaybeUninit<K>>,
+    values: Vec<MaybeUninit<V>>,
+    hashes: Vec<u64>,
+    i: usize,
+    /// Full slots not yet yielded.
+    remaining: usize,
+}
+
+impl<K: Hash + Eq, V, const G: usize> IntoIter<K, V, G> {
+    fn next_entry(&mut self) -> Option<Bucket<K, V>> {
+        while self.i < self.keys.len() {
+            let i = self.i;
+            self.i += 1;
+            if is_full(&self.ctrl, i) {
+                self.remaining -= 1;
+                return Some(unsafe {
+                    Bucket {
+                        key: self.keys[i].assume_init_read(),
+                        value: self.values[i].assume_init_read(),
+                        hash: self.hashes[i],
+                    }
+                });
+            }
+        }
+        None
+    }
+}
+
+impl<K: Hash + Eq, V, const G: usize> Iterator for IntoIter<K, V, G> {
+    type Item = (K, V);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.next_entry().map(|entry| (entry.key, entry.value))
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        (self.remaining, Some(self.remaining))
+    }
+}
+
+impl<K: Hash + Eq, V, const G: usize> ExactSizeIterator for IntoIter<K, V, G> {}
+
+impl<K: Hash + Eq, V, const G: usize> FusedIterator for IntoIter<K, V, G> {}
+
+impl<K: Hash + Eq, V, const G: usize> Drop for IntoIter<K, V, G> {
+    fn drop(&mut self) {
+        self.for_each(drop);
+    }
+}
+
+pub struct IntoKeys<K: Hash + Eq, V, const G: usize = GROUP_SIZE> {
+    inner: IntoIter<K, V, G>,
+}
+
+impl<K: Hash + Eq, V, const G: usize> Iterator for IntoKeys<K, V, G> {
+    type Item = K;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.inner.next().map(|(k, _)| k)
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        self.inner.size_hint()
+    }
+}
+
+impl<K: Hash + Eq, V, const G: usize> ExactSizeIterator for IntoKeys<K, V, G> {}
+
+impl<K: Hash + Eq, V, const G: usize> FusedIterator for IntoKeys<K, V, G> {}
+
+pub struct IntoValues<K: Hash + Eq, V, const G: usize = GROUP_SIZE> {
+    inner: IntoIter<K, V, G>,
+}
+
+impl<K: Hash + Eq, V, const G: usize> Iterator for IntoValues<K, V, G> {
+    type Item = V;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.inner.next().map(|(_, v)| v)
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        self.inner.size_hint()
+    }
+}
+
+impl<K: Hash + Eq, V, const G: usize> ExactSizeIterator for IntoValues<K, V, G> {}
+
+impl<K: Hash + Eq, V, const G: usize> FusedIterator for IntoValues<K, V, G> {}
+
+impl<K: Hash + Eq, V, S, const G: usize> IntoIterator for Map<K, V, S, G> {
+    type Item = (K, V);
+    type IntoIter = IntoIter<K, V, G>;
+
+    fn into_iter(mut self) -> Self::IntoIter {
+        // Taking the arrays leaves the map empty, so its drop is a no-op.
+        IntoIter {
+            ctrl: mem::take(&mut self.ctrl),
+            keys: mem::take(&mut self.keys),
+            values: mem::take(&mut self.values),
+            hashes: mem::take(&mut self.hashes),
+            i: 0,
+            remaining: self.count,
+        }
+    }
+}
+
+impl<'a, K: Hash + Eq, V, S: BuildHasher, const G: usize> IntoIterator for &'a Map<K, V, S, G> {
+    type Item = (&'a K, &'a V);
+    type IntoIter = Iter<'a, K, V, G>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.iter()
+    }
+}
+
+impl<'a, K: Hash + Eq, V, S: BuildHasher, const G: usize> IntoIterator for &'a mut Map<K, V, S, G> {
+    type Item = (&'a K, &'a mut V);
+    type IntoIter = IterMut<'a, K, V, G>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.iter_mut()
+    }
+}
+
+#[cfg(feature = "rayon")]
+mod rayon_impls {
+    use std::hash::Hash;
+
+    use rayon::prelude::*;
+
+    use super::Map;
+
+    impl<K: Hash + Eq, V, S, const G: usize> Map<K, V, S, G> {
+        /// Visits the entries in parallel. Each job scans whole groups, so
+        /// the range any worker covers never overlaps another's.
+        pub fn par_iter(&self) -> impl ParallelIterator<Item = (&K, &V)>
+        where
+            K: Sync,
+            V: Sync,
+        {
+            self.ctrl
+                .par_iter()
+                .zip(self.keys.par_chunks(G))
+                .zip(self.values.par_chunks(G))
+                .flat_map_iter(|((ctrl, keys), values)| {
+                    (0..G).filter(|&i| ctrl.is_full(i)).map(|i| unsafe {
+                        (keys[i].assume_init_ref(), values[i].assume_init_ref())
+                    })
+                })
+        }
+
+        /// Like `par_iter`, with the values borrowed mutably. Groups are
+        /// split into disjoint chunks of `values`, so no two workers can
+        /// reach the same slot.
+        pub fn par_iter_mut(&mut self) -> impl ParallelIterator<Item = (&K, &mut V)>
+        where
+            K: Sync,
+            V: Send,
+        {
+            self.ctrl
+                .par_iter()
+                .zip(self.keys.par_chunks(G))
+                .zip(self.values.par_chunks_mut(G))
+                .flat_map_iter(|((ctrl, keys), values)| {
+                    keys.iter()
+                        .zip(values)
+                        .enumerate()
+                        .filter(|&(i, _)| ctrl.is_full(i))
+                        .map(|(_, (key, value))| unsafe {
+                            (key.assume_init_ref(), value.assume_init_mut())
+                        })
+                })
+        }
+    }
+}
+
+#[cfg(feature = "serde")]
+mod serde_impls {
+    use std::{
+        fmt,
+        hash::{BuildHasher, Hash},
+        marker::PhantomData,
+    };
+
+    use serde::{
+        Deserialize, Deserializer, Serialize, Serializer,
+        de::{MapAccess, Visitor},
+    };
+
+    use super::{LOAD_FACTOR, Map};
+    use crate::MAX_PREALLOCATED;
+
+    impl<K, V, S, const G: usize> Serialize for Map<K, V, S, G>
+    where
+        K: Hash + Eq + Serialize,
+        V: Serialize,
+        S: BuildHasher,
+    {
+        fn serialize<T: Serializer>(&self, serializer: T) -> Result<T::Ok, T::Error> {
+            serializer.collect_map(self.iter())
+        }
+    }
+
+    impl<'de, K, V, S, const G: usize> Deserialize<'de> for Map<K, V, S, G>
+    where
+        K: Hash + Eq + Deserialize<'de>,
+        V: Deserialize<'de>,
+        S: BuildHasher + Default,
+    {
+        fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+            deserializer.deserialize_map(MapVisitor(PhantomData))
+        }
+    }
+
+    struct MapVisitor<K: Hash + Eq, V, S, const G: usize>(PhantomData<Map<K, V, S, G>>);
+
+    impl<'de, K, V, S, const G: usize> Visitor<'de> for MapVisitor<K, V, S, G>
+    where
+        K: Hash + Eq + Deserialize<'de>,
+        V: Deserialize<'de>,
+        S: BuildHasher + Default,
+    {
+        type Value = Map<K, V, S, G>;
+
+        fn expecting(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+            f.write_str("a map")
+        }
+
+        /// Later duplicates of a key overwrite earlier ones.
+        fn visit_map<A: MapAccess<'de>>(self, mut access: A) -> Result<Self::Value, A::Error> {
+            let cap = access.size_hint().unwrap_or(0).min(MAX_PREALLOCATED);
+            let mut map = Map::with_group_count(
+                Map::<K, V, S, G>::group_count_for(cap, LOAD_FACTOR),
+                S::default(),
+            );
+            while let Some((key, value)) = access.next_entry()? {
+                map.insert(key, value);
+            }
+            Ok(map)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        cell::Cell,
+        collections::{HashMap as StdHashMap, HashSet},
+        hash::Hasher,
+        panic,
+    };
+
+    use super::*;
+
+    struct CloneCounter<'a> {
+        id: i32,
+        clones: &'a Cell<usize>,
+    }
+
+    impl Clone for CloneCounter<'_> {
+        fn clone(&self) -> Self {
+            self.clones.set(self.clones.get() + 1);
+            Self {
+                id: self.id,
+                clones: self.clones,
+            }
+        }
+    }
+
+    impl PartialEq for CloneCounter<'_> {
+        fn eq(&self, other: &Self) -> bool {
+            self.id == other.id
+        }
+    }
+
+    impl Eq for CloneCounter<'_> {}
+
+    impl Hash for CloneCounter<'_> {
+        fn hash<H: Hasher>(&self, state: &mut H) {
+            self.id.hash(state);
+        }
+    }
+
+    /// Counts its drops into a shared cell, to check that every key and value
+    /// is dropped exactly once.
+    struct DropCounter<'a> {
+        id: i32,
+        drops: &'a Cell<usize>,
+    }
+
+    impl Drop for DropCounter<'_> {
+        fn drop(&mut self) {
+            self.drops.set(self.drops.get() + 1);
+        }
+    }
+
+    impl PartialEq for DropCounter<'_> {
+        fn eq(&self, other: &Self) -> bool {
+            self.id == other.id
+        }
+    }
+
+    impl Eq for DropCounter<'_> {}
+
+    impl Hash for DropCounter<'_> {
+        fn hash<H: Hasher>(&self, state: &mut H) {
+            self.id.hash(state);
+        }
+    }
+
+    fn avg_probe_length<K: Hash + Eq, V>(map: &Map<K, V>) -> f64 {
+        let mut total = 0;
+        for slot_index in 0..map.slot_count() {
+            if is_full(&map.ctrl, slot_index) {
+                let (home, _) = map.hash(map.key_at(slot_index));
+                let (group_index, _) = map.get_group_and_ctrl_indices(slot_index);
+                total += (group_index + map.group_count - home) % map.group_count;
+            }
+        }
+        total as f64 / map.count as f64
+    }
+
+    #[test]
+    fn test_insert_and_get() {
+        let mut map = Map::new();
+        map.insert("key1".to_string(), 1);
+        map.insert("key2".to_string(), 2);
+
+        assert_eq!(map.get(&"key1".to_string()), Some(&1));
+        assert_eq!(map.get(&"key2".to_string()), Some(&2));
+        assert_eq!(map.get(&"key3".to_string()), None);
+    }
+
+    #[test]
+    fn test_insert_overwrite() {
+        let mut map = Map::new();
+        map.insert("key1".to_string(), 1);
+        assert_eq!(map.get(&"key1".to_string()), Some(&1));
+
+        let old_value = map.insert("key1".to_string(), 2);
+        assert_eq!(old_value, Some(1));
+        assert_eq!(map.get(&"key1".to_string()), Some(&2));
+    }
+
+    #[test]
+    fn test_get_mut() {
+        let mut map = Map::new();
+        map.insert("key1".to_string(), 1);
+
+        let value = map.get_mut(&"key1".to_string()).unwrap();
+        *value = 10;
+
+        assert_eq!(map.get(&"key1".to_string()), Some(&10));
+    }
+
+    #[test]
+    fn test_delete() {
+        let mut map = Map::new();
+        map.insert("key1".to_string(), 1);
+        map.insert("key2".to_string(), 2);
+
+        assert_eq!(map.delete(&"key1".to_string()), Some(1));
+        assert_eq!(map.get(&"key1".to_string()), None);
+        assert_eq!(map.get(&"key2".to_string()), Some(&2));
+        assert_eq!(map.delete(&"key3".to_string()), None);
+    }
+
+    #[test]
+    fn test_expansion() {
+        let mut map = Map::new();
+        for i in 0..100 {
+            map.insert(i.to_string(), i);
+        }
+
+        assert!(map.slot_count() > 64); // Check that expansion happened
+        for i in 0..100 {
+            assert_eq!(map.get(&i.to_string()), Some(&i));
+        }
+    }
+
+    #[test]
+    fn test_delete_and_reinsert() {
+        let mut map = Map::new();
+        map.insert("key1".to_string(), 1);
+        assert_eq!(map.delete(&"key1".to_string()), Some(1));
+        assert_eq!(map.get(&"key1".to_string()), None);
+        map.insert("key1".to_string(), 2);
+        assert_eq!(map.get(&"key1".to_string()), Some(&2));
+    }
+
+    #[test]
+    fn test_many_insertions_and_deletions() {
+        let mut map = Map::new();
+        for i in 0..1000 {
+            map.insert(i, i);
+        }
+        for i in 0..1000 {
+            assert_eq!(map.get(&i), Some(&i));
+        }
+        for i in (0..1000).step_by(2) {
+            assert_eq!(map.delete(&i), Some(i));
+        }
+        for i in 0..1000 {
+            if i % 2 == 0 {
+                assert_eq!(map.get(&i), None);
+            } else {
+                assert_eq!(map.get(&i), Some(&i));
+            }
+        }
+    }
+
+    #[test]
+    fn test_contains() {
+        let mut map = Map::new();
+        map.insert("a".to_string(), 1);
+        assert!(map.contains(&"a".to_string()));
+        assert!(!map.contains(&"b".to_string()));
+    }
+
+    #[test]
+    fn test_iter() {
+        let mut map = Map::new();
+        map.insert("a".to_string(), 1);
+        map.insert("b".to_string(), 2);
+        let mut std_map = StdHashMap::new();
+        std_map.insert("a".to_string(), 1);
+        std_map.insert("b".to_string(), 2);
+
+        let mut count = 0;
+        for (k, v) in map.iter() {
+            assert_eq!(std_map.get(k), Some(v));
+            count += 1;
+        }
+        assert_eq!(count, 2);
+    }
+
+    #[test]
+    fn test_iter_mut() {
+        let mut map = Map::new();
+        map.insert("a".to_string(), 1);
+        map.insert("b".to_string(), 2);
+
+        for (_, v) in map.iter_mut() {
+            *v *= 2;
+        }
+
+        assert_eq!(map.get(&"a".to_string()), Some(&2));
+        assert_eq!(map.get(&"b".to_string()), Some(&4));
+    }
+
+    #[test]
+    fn test_exact_size() {
+        let mut map = Map::new();
+        for i in 0..100 {
+            map.insert(i, i);
+        }
+        for i in (0..100).step_by(4) {
+            map.delete(&i);
+        }
+
+        let mut iter = map.iter();
+        assert_eq!(iter.len(), map.len());
+        for remaining in (0..map.len()).rev() {
+            iter.next();
+            assert_eq!(iter.len(), remaining);
+        }
+        assert_eq!(iter.next(), None);
+        assert_eq!(iter.len(), 0);
+
+        let mut iter_mut = map.iter_mut();
+        iter_mut.nth(9);
+        assert_eq!(iter_mut.len(), 65);
+
+        let mut into_iter = map.into_iter();
+        assert_eq!(into_iter.len(), 75);
+        into_iter.next();
+        assert_eq!(into_iter.len(), 74);
+        assert_eq!(into_iter.collect::<Vec<_>>().len(), 74);
+    }
+
+    #[test]
+    fn test_into_iter_refs() {
+        let mut map = Map::new();
+        for i in 0..10 {
+            map.insert(i, i);
+        }
+
+        for (_, v) in &mut map {
+            *v *= 3;
+        }
+        let mut sum = 0;
+        for (k, v) in &map {
+            assert_eq!(*v, k * 3);
+            sum += v;
+        }
+        assert_eq!(sum, 135);
+    }
+
+    #[test]
+    fn test_set() {
+        let mut set = Set::new();
+        assert!(set.is_empty());
+        assert!(set.insert("a".to_string()));
+        assert!(set.insert("b".to_string()));
+        assert!(!set.insert("a".to_string()));
+        assert_eq!(set.len(), 2);
+        assert!(set.contains("a"));
+        assert!(!set.contains("c"));
+
+        assert!(set.remove("a"));
+        assert!(!set.remove("a"));
+        assert!(!set.contains("a"));
+        assert_eq!(set.iter().collect::<Vec<_>>(), ["b"]);
+        assert_eq!(set.into_iter().collect::<Vec<_>>(), ["b"]);
+    }
+
+    #[test]
+    fn test_set_algebra() {
+        fn sorted<'a>(values: impl Iterator<Item = &'a i32>) -> Vec<i32> {
+            let mut values: Vec<_> = values.copied().collect();
+            values.sort();
+            values
+        }
+
+        let a: Set<i32> = (0..10).collect();
+        let b: Set<i32> = (5..15).collect();
+        assert_eq!(sorted(a.union(&b)), (0..15).collect::<Vec<_>>());
+        assert_eq!(sorted(a.intersection(&b)), (5..10).collect::<Vec<_>>());
+        assert_eq!(sorted(a.difference(&b)), (0..5).collect::<Vec<_>>());
+        assert_eq!(sorted(b.difference(&a)), (10..15).collect::<Vec<_>>());
+        assert_eq!(a.union(&a).count(), 10);
+        assert_eq!(a.intersection(&Set::new()).count(), 0);
+    }
+
+    #[test]
+    fn test_size_hint() {
+        let mut map = Map::new();
+        for i in 0..50 {
+            map.insert(i, i);
+        }
+        map.delete(&7);
+        let len = map.len();
+
+        assert_eq!(map.iter().size_hint(), (len, Some(len)));
+        assert_eq!(map.iter_mut().size_hint(), (len, Some(len)));
+        assert_eq!(map.keys().size_hint(), (len, Some(len)));
+        assert_eq!(map.values().size_hint(), (len, Some(len)));
+        assert_eq!(map.values_mut().size_hint(), (len, Some(len)));
+
+        let mut values = map.values();
+        values.next();
+        assert_eq!(values.size_hint(), (len - 1, Some(len - 1)));
+
+        let mut drain = map.drain();
+        assert_eq!(drain.size_hint(), (len, Some(len)));
+        drain.nth(4);
+        assert_eq!(drain.size_hint(), (len - 5, Some(len - 5)));
+        drop(drain);
+
+        map.extend((0..10).map(|i| (i, i)));
+        assert_eq!(map.into_iter().size_hint(), (10, Some(10)));
+    }
+
+    #[test]
+    fn test_iter_rev() {
+        let mut map = Map::new();
+        for i in 0..500 {
+            map.insert(i, i * 2);
+        }
+        for i in (0..500).step_by(7) {
+            map.delete(&i);
+        }
+
+        let forward: Vec<_> = map.iter().collect();
+        let mut backward: Vec<_> = map.iter().rev().collect();
+        backward.reverse();
+        assert_eq!(backward, forward);
+
+        // Taking from both ends in turn meets in the middle without repeats.
+        let mut iter = map.iter();
+        let mut seen = HashSet::new();
+        while let Some((k, _)) = iter.next() {
+            assert!(seen.insert(*k));
+            if let Some((k, _)) = iter.next_back() {
+                assert!(seen.insert(*k));
+            }
+            assert_eq!(iter.len(), map.len() - seen.len());
+        }
+        assert_eq!(seen.len(), map.len());
+        assert_eq!(iter.next_back(), None);
+    }
+
+    #[test]
+    fn test_fused() {
+        let mut map = Map::new();
+        for i in 0..3 {
+            map.insert(i, i);
+        }
+
+        let mut iter = map.iter();
+        assert_eq!(iter.by_ref().count(), 3);
+        assert!((0..5).all(|_| iter.next().is_none()));
+
+        let mut iter_mut = map.iter_mut();
+        assert_eq!(iter_mut.by_ref().count(), 3);
+        assert!((0..5).all(|_| iter_mut.next().is_none()));
+
+        let mut keys = map.keys();
+        assert_eq!(keys.by_ref().count(), 3);
+        assert!((0..5).all(|_| keys.next().is_none()));
+
+        let mut drain = map.drain();
+        assert_eq!(drain.by_ref().count(), 3);
+        assert!((0..5).all(|_| drain.next().is_none()));
+        drop(drain);
+
+        map.insert(1, 1);
+        let mut into_iter = map.into_iter();
+        assert_eq!(into_iter.next(), Some((1, 1)));
+        assert!((0..5).all(|_| into_iter.next().is_none()));
+    }
+
+    #[test]
+    fn test_into_iter() {
+        let mut map = Map::new();
+        map.insert("a".to_string(), 1);
+        map.insert("b".to_string(), 2);
+        let mut std_map = StdHashMap::new();
+        std_map.insert("a".to_string(), 1);
+        std_map.insert("b".to_string(), 2);
+
+        let mut count = 0;
+        for (k, v) in map.into_iter() {
+            assert_eq!(std_map.get(&k), Some(&v));
+            count += 1;
+        }
+        assert_eq!(count, 2);
+    }
+
+    #[test]
+    fn test_into_keys_and_values() {
+        let map: Map<_, _> = (0..100).map(|i| (i.to_string(), i % 10)).collect();
+        let mut keys: Vec<_> = map.into_keys().collect();
+        keys.sort_by_key(|k| k.parse::<i32>().unwrap());
+        assert_eq!(keys, (0..100).map(|i| i.to_string()).collect::<Vec<_>>());
+
+        let map: Map<_, _> = (0..100).map(|i| (i.to_string(), i % 10)).collect();
+        let values = map.into_values();
+        assert_eq!(values.len(), 100);
+        let mut values: Vec<_> = values.collect();
+        values.sort();
+        let mut expected: Vec<_> = (0..100).map(|i| i % 10).collect();
+        expected.sort();
+        assert_eq!(values, expected);
+    }
+
+    #[test]
+    fn test_entry() {
+        let mut map = Map::new();
+        *map.entry("a".to_string()).or_insert(1) += 10;
+        assert_eq!(map.get("a"), Some(&11));
+        assert_eq!(*map.entry("a".to_string()).or_insert(100), 11);
+
+        map.entry("a".to_string())
+            .and_modify(|v| *v += 1)
+            .or_insert(0);
+        map.entry("b".to_string())
+            .and_modify(|v| *v += 1)
+            .or_insert(0);
+        assert_eq!(map.get("a"), Some(&12));
+        assert_eq!(map.get("b"), Some(&0));
+
+        match map.entry("a".to_string()) {
+            Entry::Occupied(mut entry) => {
+                assert_eq!(entry.key(), "a");
+                assert_eq!(entry.insert(5), 12);
+                assert_eq!(entry.remove(), 5);
+            }
+            Entry::Vacant(_) => panic!("expected an occupied entry"),
+        }
+        assert!(!map.contains_key("a"));
+        assert_eq!(map.len(), 1);
+
+        // Dropping a vacant entry inserts nothing and does not grow the table.
+        let slot_count = map.slot_count();
+        for i in 0..10_000 {
+            assert!(matches!(map.entry(i.to_string()), Entry::Vacant(_)));
+        }
+        assert_eq!(map.len(), 1);
+        assert_eq!(map.slot_count(), slot_count);
+
+        for i in 0..10_000 {
+            map.entry(i.to_string()).or_insert(i);
+        }
+        assert_eq!(map.len(), 10_001);
+        for i in 0..10_000 {
+            assert_eq!(map.get(&i.to_string()), Some(&i));
+        }
+    }
+
+    #[test]
+    fn test_insert_and_get_returns_value() {
+        let mut map = Map::new();
+        for i in 0..=map.capacity() {
+            map.insert(i, vec![i as i32]);
+        }
+        let handle = map.slot_ref(&7).unwrap();
+
+        // Replacing a value at the load limit neither grows the table nor
+        // moves the entry.
+        map.insert_and_get(7, Vec::new()).push(70);
+        assert_eq!(map.resize_count(), 0);
+        assert_eq!(map.get_at(&handle), Some((&7, &vec![70])));
+
+        // A new key makes room first, and the reference points into the grown
+        // table.
+        map.insert_and_get(100, vec![100]).push(-100);
+        assert_eq!(map.resize_count(), 1);
+        assert_eq!(map.get(&100), Some(&vec![100, -100]));
+        assert_eq!(map.get(&7), Some(&vec![70]));
+    }
+
+    #[test]
+    fn test_try_insert() {
+        let mut map = Map::new();
+        for i in 0..=map.capacity() {
+            map.insert(i, i);
+        }
+        assert_eq!(map.resize_count(), 0);
+
+        // The table is at its load limit, but a key that is already there is
+        // found before any room is made.
+        let error = map.try_insert(3, 30).unwrap_err();
+        assert_eq!((error.entry.get(), error.value), (&3, 30));
+        assert_eq!(
+            error.to_string(),
+            "failed to insert 30, key 3 already exists with value 3"
+        );
+        assert_eq!(map.resize_count(), 0);
+
+        *map.try_insert(100, 100).unwrap() += 1;
+        assert_eq!(map.resize_count(), 1);
+        assert_eq!(map.get(&100), Some(&101));
+        assert_eq!(map.get(&3), Some(&3));
+    }
+
+    #[test]
+    fn test_raw_entry_mut() {
+        let mut map = Map::new();
+        for i in 0..1000 {
+            let key = i.to_string();
+            let hash = map.hasher().hash_one(&key);
+            match map.raw_entry_mut().from_hash(hash, |k| *k == key) {
+                RawEntryMut::Occupied(_) => panic!("expected a vacant entry"),
+                RawEntryMut::Vacant(entry) => {
+                    let (k, v) = entry.insert(key.clone(), i);
+                    assert_eq!((k, *v), (&key, i));
+                }
+            }
+        }
+        assert_eq!(map.len(), 1000);
+        for i in 0..1000 {
+            assert_eq!(map.get(&i.to_string()), Some(&i));
+        }
+
+        // Look a String key up by &str, comparing contents without building a key.
+        let hash = map.hasher().hash_one("500");
+        match map.raw_entry_mut().from_hash(hash, |k| k == "500") {
+            RawEntryMut::Occupied(mut entry) => {
+                assert_eq!(entry.key(), "500");
+                assert_eq!(entry.insert(-500), 500);
+            }
+            RawEntryMut::Vacant(_) => panic!("expected an occupied entry"),
+        }
+        assert_eq!(map.get("500"), Some(&-500));
+
+        let hash = map.hasher().hash_one("500");
+        let (_, v) = map
+            .raw_entry_mut()
+            .from_hash(hash, |k| k == "500")
+            .or_insert_with(|| unreachable!());
+        *v = 5;
+        assert_eq!(map.get("500"), Some(&5));
+
+        let hash = map.hasher().hash_one("500");
+        let RawEntryMut::Occupied(entry) = map.raw_entry_mut().from_hash(hash, |k| k == "500")
+        else {
+            panic!("expected an occupied entry");
+        };
+        assert_eq!(entry.remove_entry(), ("500".to_string(), 5));
+        assert_eq!(map.get("500"), None);
+        assert_eq!(map.len(), 999);
+    }
+
+    #[test]
+    fn test_entry_ref() {
+        let text = "the quick fox jumps over the lazy dog the end";
+        let mut counts: Map<String, usize> = Map::new();
+        for word in text.split_whitespace() {
+            *counts.entry_ref(word).or_default() += 1;
+        }
+        assert_eq!(counts.len(), 8);
+        assert_eq!(counts.get("the"), Some(&3));
+        match counts.entry_ref("cat") {
+            EntryRef::Occupied(_) => panic!("expected a vacant entry"),
+            EntryRef::Vacant(entry) => assert_eq!(entry.key(), "cat"),
+        }
+        assert!(!counts.contains_key("cat"));
+    }
+
+    #[test]
+    fn test_entry_ref_allocates_only_on_insert() {
+        use std::sync::atomic::{AtomicUsize, Ordering};
+
+        static TO_OWNED_CALLS: AtomicUsize = AtomicUsize::new(0);
+
+        // The borrowed form is not Clone, so the counted to_owned is the only way to
+        // get an owned key.
+        #[derive(Hash, PartialEq, Eq)]
+        struct Id(u32);
+
+        #[derive(Hash, PartialEq, Eq)]
+        struct OwnedId(Id);
+
+        impl Borrow<Id> for OwnedId {
+            fn borrow(&self) -> &Id {
+                &self.0
+            }
+        }
+
+        impl ToOwned for Id {
+            type Owned = OwnedId;
+
+            fn to_owned(&self) -> OwnedId {
+                TO_OWNED_CALLS.fetch_add(1, Ordering::Relaxed);
+                OwnedId(Id(self.0))
+            }
+        }
+
+        let mut map = Map::new();
+        for round in 0..3 {
+            for i in 0..100 {
+                *map.entry_ref(&Id(i)).or_insert(0) += 1;
+            }
+            assert_eq!(TO_OWNED_CALLS.load(Ordering::Relaxed), 100, "round {round}");
+        }
+        assert_eq!(map.len(), 100);
+        assert_eq!(map.get(&Id(42)), Some(&3));
+    }
+
+    #[test]
+    fn test_entry_or_default() {
+        let text = "the quick fox jumps over the lazy dog the end";
+        let mut counts: Map<&str, usize> = Map::new();
+        for word in text.split_whitespace() {
+            *counts.entry(word).or_default() += 1;
+        }
+        assert_eq!(counts.len(), 8);
+        assert_eq!(counts.get("the"), Some(&3));
+        assert_eq!(counts.get("fox"), Some(&1));
+    }
+
+    #[test]
+    fn test_entry_or_insert_with_key() {
+        // The key type is not Clone, so compiling at all shows no key is copied.
+        #[derive(Hash, PartialEq, Eq)]
+        struct Name(String);
+
+        let mut map = Map::new();
+        let len = map
+            .entry(Name("swiss".to_string()))
+            .or_insert_with_key(|name| name.0.len());
+        assert_eq!(*len, 5);
+        let len = map
+            .entry(Name("swiss".to_string()))
+            .or_insert_with_key(|_| unreachable!());
+        assert_eq!(*len, 5);
+        assert_eq!(map.get(&Name("swiss".to_string())), Some(&5));
+    }
+
+    #[test]
+    fn test_collision_stress() {
+        let mut map = Map::new();
+        let n = if cfg!(miri) { 100 } else { 10000 };
+
+        for i in 0..n {
+            map.insert(i, i);
+        }
+
+        for i in 0..n {
+            assert_eq!(map.get(&i), Some(&i));
+        }
+
+        for i in (0..n).step_by(2) {
+            assert_eq!(map.delete(&i), Some(i));
+        }
+
+        for i in 0..n {
+            if i % 2 == 0 {
+                assert_eq!(map.get(&i), None);
+            } else {
+                assert_eq!(map.get(&i), Some(&i));
+            }
+        }
+
+        for i in (0..n).step_by(2) {
+            map.insert(i, i * 2);
+        }
+
+        for i in 0..n {
+            if i % 2 == 0 {
+                assert_eq!(map.get(&i), Some(&(i * 2)));
+            } else {
+                assert_eq!(map.get(&i), Some(&i));
+            }
+        }
+    }
+
+    #[test]
+    fn test_high_load_delete_reinsert() {
+        let mut map = Map::new();
+        let n = 1000;
+
+        // Fill the map to a high load factor
+        for i in 0..n {
+            map.insert(i, i);
+        }
+
+        // Delete a large portion of the items
+        for i in (0..n).step_by(2) {
+            assert_eq!(map.delete(&i), Some(i));
+        }
+
+        // Check that the remaining items are still there
+        for i in 0..n {
+            if i % 2 == 0 {
+                assert_eq!(map.get(&i), None);
+            } else {
+                assert_eq!(map.get(&i), Some(&i));
+            }
+        }
+
+        // Re-insert all items, some will be new, some will overwrite tombstones
+        for i in 0..n {
+            map.insert(i, i * 10);
+        }
+
+        // Check that all items are present with their new values
+        for i in 0..n {
+            assert_eq!(map.get(&i), Some(&(i * 10)));
+        }
+    }
+
+    #[test]
+    fn test_ideal_capacity() {
+        let mut map = Map::new();
+        assert_eq!(map.ideal_capacity(), map.slot_count());
+
+        for i in 0..10000 {
+            map.insert(i, i);
+        }
+        for i in 10..10000 {
+            map.delete(&i);
+        }
+
+        assert_eq!(map.ideal_capacity(), INITIAL_GROUP_COUNT * GROUP_SIZE);
+        assert!(map.ideal_capacity() * 100 < map.slot_count());
+    }
+
+    #[test]
+    fn test_map_builder() {
+        let mut builder: MapBuilder<_, _> = MapBuilder::with_chunk_size(100);
+        let mut map = Map::new();
+        for i in 0..5000 {
+            builder.push(i.to_string(), i);
+            map.insert(i.to_string(), i);
+        }
+        builder.push("0".to_string(), -1);
+        map.insert("0".to_string(), -1);
+
+        let built = builder.finish();
+        assert_eq!(built.count, map.count);
+        assert_eq!(built.slot_count(), built.ideal_capacity());
+        for (k, v) in map.iter() {
+            assert_eq!(built.get(k), Some(v));
+        }
+
+        // Far fewer entries than one chunk do not keep the chunk's room.
+        for (chunk_size, n) in [(100_000, 100), (1024, 64)] {
+            let mut builder: MapBuilder<_, _> = MapBuilder::with_chunk_size(chunk_size);
+            for i in 0..n {
+                builder.push(i, i);
+            }
+            let built = builder.finish();
+            assert_eq!(built.len(), n);
+            assert_eq!(built.slot_count(), built.ideal_capacity());
+            let collected: Map<_, _> = (0..n).map(|i| (i, i)).collect();
+            assert_eq!(built.slot_count(), collected.slot_count());
+        }
+    }
+
+    /// Returns how many entries fit before the table first grows.
+    fn entries_before_expansion(mut map: Map<i32, i32>) -> usize {
+        let slot_count = map.slot_count();
+        let mut i = 0;
+        while map.slot_count() == slot_count {
+            map.insert(i, i);
+            i += 1;
+        }
+        i as usize - 1
+    }
+
+    #[test]
+    fn test_builder_load_factor() {
+        let default = entries_before_expansion(MapBuilder::new().finish());
+        let sparse = MapBuilder::new().with_load_factor(0.5).finish();
+        assert_eq!(sparse.slot_count(), INITIAL_GROUP_COUNT * GROUP_SIZE);
+        let sparse = entries_before_expansion(sparse);
+        assert_eq!(sparse, INITIAL_GROUP_COUNT * GROUP_SIZE / 2);
+        assert!(sparse < default);
+
+        let mut builder: MapBuilder<_, _> = MapBuilder::with_chunk_size(100).with_load_factor(0.5);
+        for i in 0..1000 {
+            builder.push(i, i);
+        }
+        let map = builder.finish();
+        assert!(map.count as f64 / map.slot_count() as f64 <= 0.5);
+        assert_eq!(map.slot_count(), map.ideal_capacity());
+    }
+
+    #[test]
+    #[should_panic(expected = "load factor must be between 0 and 1")]
+    fn test_builder_rejects_zero_load_factor() {
+        MapBuilder::<i32, i32>::new().with_load_factor(0.0);
+    }
+
+    #[test]
+    #[should_panic(expected = "load factor must be between 0 and 1")]
+    fn test_builder_rejects_full_load_factor() {
+        MapBuilder::<i32, i32>::new().with_load_factor(1.0);
+    }
+
+    #[test]
+    fn test_validate_handle() {
+        let mut map = Map::new();
+        map.insert("a".to_string(), 1);
+        map.insert("b".to_string(), 2);
+
+        let handle = map.slot_ref(&"a".to_string()).unwrap();
+        assert!(map.validate_handle(&handle, &"a".to_string()));
+        assert!(!map.validate_handle(&handle, &"b".to_string()));
+        assert_eq!(map.get_at(&handle), Some((&"a".to_string(), &1)));
+
+        for i in 0..100 {
+            map.insert(i.to_string(), i);
+        }
+        assert!(!map.validate_handle(&handle, &"a".to_string()));
+        assert_eq!(map.get_at(&handle), None);
+
+        let handle = map.slot_ref(&"b".to_string()).unwrap();
+        map.delete(&"b".to_string());
+        assert!(!map.validate_handle(&handle, &"b".to_string()));
+    }
+
+    #[test]
+    fn test_get_at_after_slot_reuse() {
+        let mut map = Map::new();
+        for i in 0..10 {
+            map.insert(i, i);
+        }
+        let handle = map.slot_ref(&3).unwrap();
+        map.delete(&3);
+        for i in 100..=103 {
+            map.insert(i, i);
+        }
+        assert_eq!(map.get_at(&handle), None);
+
+        // A same-size rehash moves entries without changing the slot count.
+        let handle = map.slot_ref(&5).unwrap();
+        let slot_count = map.slot_count();
+        map.shrink_to_fit();
+        assert_eq!(map.slot_count(), slot_count);
+        assert_eq!(map.get_at(&handle), None);
+        let handle = map.slot_ref(&5).unwrap();
+        assert_eq!(map.get_at(&handle), Some((&5, &5)));
+    }
+
+    #[test]
+    fn test_adaptive_load_factor() {
+        let mut fixed = Map::new();
+        let mut adaptive = Map::new();
+        adaptive.set_adaptive_load_factor(true);
+        for i in 0..55000 {
+            fixed.insert(i, i);
+            adaptive.insert(i, i);
+        }
+
+        assert_eq!(fixed.slot_count(), 1 << 16);
+        assert_eq!(adaptive.slot_count(), 1 << 17);
+        assert!(avg_probe_length(&adaptive) < avg_probe_length(&fixed));
+        for i in 0..55000 {
+            assert_eq!(adaptive.get(&i), Some(&i));
+        }
+    }
+
+    #[test]
+    fn test_iter_prefix() {
+        let mut map = Map::new();
+        for key in ["apple", "apricot", "banana", "app", "ap", "cherry"] {
+            map.insert(key.to_string(), key.len());
+        }
+
+        let mut matches: Vec<_> = map
+            .iter_prefix("app")
+            .map(|(k, v)| (k.as_str(), *v))
+            .collect();
+        matches.sort();
+        assert_eq!(matches, vec![("app", 3), ("apple", 5)]);
+        assert_eq!(map.iter_prefix("").count(), 6);
+        assert_eq!(map.iter_prefix("z").count(), 0);
+    }
+
+    #[test]
+    fn test_reserve_peak() {
+        let mut map = Map::new();
+        map.reserve_peak(10000);
+        let size = map.slot_count();
+        for i in 0..10000 {
+            map.insert(i, i);
+        }
+        assert_eq!(map.slot_count(), size);
+
+        for i in 3000..10000 {
+            map.delete(&i);
+        }
+        assert!(!map.auto_shrink());
+        assert_eq!(map.slot_count(), size);
+
+        for i in 2000..3000 {
+            map.delete(&i);
+        }
+        assert!(map.auto_shrink());
+        assert!(map.slot_count() < size);
+        for i in 0..2000 {
+            assert_eq!(map.get(&i), Some(&i));
+        }
+    }
+
+    #[test]
+    fn test_apply_updates() {
+        let mut map = Map::new();
+        map.insert("a", 1);
+        map.insert("b", 2);
+        map.insert("c", 3);
+
+        map.apply_updates([
+            ("a", Update::Modify(Box::new(|v: &mut i32| *v += 10))),
+            ("b", Update::Delete),
+            ("d", Update::Set(4)),
+            ("c", Update::Set(30)),
+            ("e", Update::Modify(Box::new(|v: &mut i32| *v += 10))),
+            ("x", Update::Delete),
+        ]);
+
+        assert_eq!(map.count, 3);
+        assert_eq!(map.get(&"a"), Some(&11));
+        assert_eq!(map.get(&"b"), None);
+        assert_eq!(map.get(&"c"), Some(&30));
+        assert_eq!(map.get(&"d"), Some(&4));
+        assert_eq!(map.get(&"e"), None);
+    }
+
+    #[test]
+    fn test_key_symmetric_difference_len() {
+        let mut a = Map::new();
+        let mut b = Map::new();
+        for i in 0..100 {
+            a.insert(i, i);
+        }
+        for i in 50..300 {
+            b.insert(i, -i);
+        }
+
+        let mut expected = 0;
+        for (k, _) in a.iter() {
+            if !b.contains(k) {
+                expected += 1;
+            }
+        }
+        for (k, _) in b.iter() {
+            if !a.contains(k) {
+                expected += 1;
+            }
+        }
+        assert_eq!(expected, 250);
+        assert_eq!(a.key_symmetric_difference_len(&b), expected);
+        assert_eq!(b.key_symmetric_difference_len(&a), expected);
+        assert_eq!(a.key_symmetric_difference_len(&a), 0);
+    }
+
+    #[cfg(feature = "debug-internals")]
+    #[test]
+    fn test_rebuild_ctrl() {
+        let mut map = Map::new();
+        for i in 0..500 {
+            map.insert(i, i);
+        }
+        for i in (0..500).step_by(3) {
+            map.delete(&i);
+        }
+
+        let ctrl: Vec<_> = map.ctrl.iter().map(|c| c.0).collect();
+        map.rebuild_ctrl();
+        assert_eq!(map.ctrl.iter().map(|c| c.0).collect::<Vec<_>>(), ctrl);
+        for i in 0..500 {
+            assert_eq!(map.get(&i), (i % 3 != 0).then_some(&i));
+        }
+    }
+
+    #[test]
+    fn test_cursor_mut() {
+        let mut map = Map::new();
+        for i in 0..100 {
+            map.insert(i, i);
+        }
+
+        let mut visited = 0;
+        let mut cursor = map.cursor_mut();
+        while let Some((_, v)) = cursor.current() {
+            visited += 1;
+            if *v % 2 == 1 {
+                assert!(cursor.remove_current().is_some());
+            } else {
+                *v *= 10;
+                cursor.advance();
+            }
+        }
+        assert_eq!(cursor.remove_current(), None);
+
+        assert_eq!(visited, 100);
+        assert_eq!(map.count, 50);
+        for i in 0..100 {
+            assert_eq!(map.get(&i), (i % 2 == 0).then_some(&(i * 10)));
+        }
+    }
+
+    #[test]
+    fn test_overhead_ratio() {
+        let mut map = Map::new();
+        assert_eq!(map.overhead_ratio(), f64::INFINITY);
+
+        let mut last = f64::INFINITY;
+        for i in 0..50u64 {
+            map.insert(i, i);
+            let ratio = map.overhead_ratio();
+            assert!(ratio < last);
+            last = ratio;
+        }
+        assert!(last > 1.0);
+    }
+
+    #[test]
+    fn test_build_bucketed() {
+        let mut pairs: Vec<_> = (0..10000).map(|i| (format!("key{}", i), i)).collect();
+        pairs.push(("key0".to_string(), -1));
+        let std_map: StdHashMap<_, _> = pairs.iter().cloned().collect();
+
+        let map = Map::build_bucketed(pairs);
+        assert_eq!(map.count, std_map.len());
+        assert_eq!(map.slot_count(), map.ideal_capacity());
+        for (key, value) in &std_map {
+            assert_eq!(map.get(key), Some(value));
+        }
+        assert_eq!(map.get(&"key0".to_string()), Some(&-1));
+    }
+
+    #[test]
+    fn test_estimate_capacity() {
+        let keys: Vec<_> = (0..100_000).map(|i| format!("key{}", i)).collect();
+        let estimate = Map::<String, ()>::estimate_capacity(keys.iter().chain(&keys));
+        assert!(
+            estimate.abs_diff(100_000) <= 5000,
+            "estimate was {}",
+            estimate
+        );
+    }
+
+    #[test]
+    fn test_resize_count() {
+        let mut map = Map::new();
+        let threshold = (map.slot_count() as f64 * LOAD_FACTOR) as usize + 1;
+        for i in 0..threshold {
+            map.insert(i, i);
+        }
+        assert_eq!(map.resize_count(), 0);
+
+        map.insert(threshold, threshold);
+        assert_eq!(map.resize_count(), 1);
+    }
+
+    #[test]
+    fn test_merge_deterministic() {
+        fn build(range: std::ops::Range<i32>, offset: i32) -> Map<i32, i32> {
+            let mut map = Map::new();
+            for i in range {
+                map.insert(i, (i * 7 + offset) % 10);
+            }
+            map
+        }
+
+        let mut ab = build(0..60, 1);
+        ab.merge_deterministic(build(30..90, 4));
+        let mut ba = build(30..90, 4);
+        ba.merge_deterministic(build(0..60, 1));
+        assert_eq!(ab.count, 90);
+        assert_eq!(ba.count, 90);
+        for (k, v) in ab.iter() {
+            assert_eq!(ba.get(k), Some(v));
+        }
+        assert_eq!(ab.get(&40), Some(&4));
+
+        let mut ab = build(0..60, 1);
+        ab.merge_deterministic_min(build(30..90, 4));
+        let mut ba = build(30..90, 4);
+        ba.merge_deterministic_min(build(0..60, 1));
+        for (k, v) in ab.iter() {
+            assert_eq!(ba.get(k), Some(v));
+        }
+        assert_eq!(ab.get(&40), Some(&1));
+    }
+
+    #[test]
+    fn test_get_or_insert_with() {
+        let mut map: Map<_, _> = (0..1000).map(|i| (i, i)).collect();
+        for i in 0..500 {
+            map.delete(&i);
+        }
+        let (slot_count, resizes) = (map.slot_count(), map.resize_count());
+
+        let calls = Cell::new(0);
+        for i in 0..1000 {
+            *map.get_or_insert_with(i, || {
+                calls.set(calls.get() + 1);
+                -i
+            }) += 1;
+        }
+        assert_eq!(calls.get(), 500);
+        assert_eq!(map.len(), 1000);
+        assert_eq!(map.get(&7), Some(&-6));
+        assert_eq!(map.get(&700), Some(&701));
+        // Every slot ahead of a deleted entry on its probe path is full or
+        // deleted itself, so each refilled key lands in a tombstone.
+        assert_eq!(map.deleted, 0);
+        assert_eq!(
+            (map.slot_count(), map.resize_count()),
+            (slot_count, resizes)
+        );
+    }
+
+    #[test]
+    fn test_get_or_insert_ref() {
+        let clones = Cell::new(0);
+        let key = |id| CloneCounter {
+            id,
+            clones: &clones,
+        };
+        let mut map = Map::new();
+
+        *map.get_or_insert_ref(&key(1), || 10) += 1;
+        assert_eq!(clones.get(), 1);
+
+        for _ in 0..100 {
+            *map.get_or_insert_ref(&key(1), || unreachable!()) += 1;
+        }
+        assert_eq!(clones.get(), 1);
+        assert_eq!(map.get(&key(1)), Some(&111));
+
+        for id in 2..100 {
+            map.get_or_insert_ref(&key(id), || id);
+        }
+        assert_eq!(clones.get(), 99);
+        assert_eq!(map.count, 99);
+        assert_eq!(map.get(&key(50)), Some(&50));
+    }
+
+    #[test]
+    fn test_key_fingerprint() {
+        let mut a = Map::new();
+        let mut b = Map::new();
+        for i in 0..100 {
+            a.insert(i, i);
+            b.insert(99 - i, -i);
+        }
+        let fingerprint = a.key_fingerprint();
+        assert_eq!(b.key_fingerprint(), fingerprint);
+
+        a.insert(1000, 0);
+        assert_ne!(a.key_fingerprint(), fingerprint);
+        a.delete(&1000);
+        assert_eq!(a.key_fingerprint(), fingerprint);
+    }
+
+    #[test]
+    fn test_grow_to_at_least() {
+        let mut map = Map::new();
+        for i in 0..50 {
+            map.insert(i, i);
+        }
+        map.grow_to_at_least(100_000);
+        assert_eq!(map.slot_count(), 1 << 17);
+        assert_eq!(map.resize_count(), 1);
+        for i in 0..50 {
+            assert_eq!(map.get(&i), Some(&i));
+        }
+
+        for i in 50..100_000 {
+            map.insert(i, i);
+        }
+        assert_eq!(map.resize_count(), 1);
+
+        map.grow_to_at_least(100);
+        assert_eq!(map.slot_count(), 1 << 17);
+    }
+
+    #[test]
+    fn test_rehash_incremental() {
+        let mut expanded = Map::new();
+        let mut incremental = Map::new();
+        for i in 0..50 {
+            expanded.insert(i, i);
+            incremental.insert(i, i);
+        }
+
+        let mut reports = Vec::new();
+        incremental.rehash_incremental(15, |done, total| reports.push((done, total)));
+        expanded.expand();
+
+        assert_eq!(reports, vec![(15, 50), (30, 50), (45, 50), (50, 50)]);
+        assert_eq!(incremental.slot_count(), expanded.slot_count());
+        assert_eq!(incremental.count, expanded.count);
+        for (k, v) in expanded.iter() {
+            assert_eq!(incremental.get(k), Some(v));
+        }
+    }
+
+    #[test]
+    fn test_keys_and_values() {
+        let mut map = Map::new();
+        map.insert("a".to_string(), 1);
+        map.insert("b".to_string(), 2);
+        map.insert("c".to_string(), 3);
+
+        let keys: HashSet<_> = map.keys().cloned().collect();
+        assert_eq!(keys.len(), 3);
+        assert!(["a", "b", "c"].iter().all(|k| keys.contains(*k)));
+
+        for v in map.values_mut() {
+            *v *= 10;
+        }
+        let mut values: Vec<_> = map.values().copied().collect();
+        values.sort();
+        assert_eq!(values, vec![10, 20, 30]);
+    }
+
+    #[test]
+    fn test_contains_key() {
+        let mut map = Map::new();
+        map.insert("a".to_string(), 1);
+        map.insert("b".to_string(), 2);
+        assert!(map.contains_key(&"a".to_string()));
+        assert!(!map.contains_key(&"c".to_string()));
+
+        map.delete(&"a".to_string());
+        assert!(!map.contains_key(&"a".to_string()));
+        assert!(map.contains_key(&"b".to_string()));
+    }
+
+    #[test]
+    fn test_default() {
+        let map = Map::<String, i32>::default();
+        assert_eq!(map.len(), 0);
+        assert!(map.is_empty());
+    }
+
+    #[test]
+    fn test_debug() {
+        let mut map = Map::new();
+        map.insert("a".to_string(), 1);
+        map.insert("b".to_string(), 2);
+
+        let output = format!("{:?}", map);
+        assert!(output.contains("\"a\": 1"));
+        assert!(output.contains("\"b\": 2"));
+        assert_eq!(format!("{:?}", Map::<i32, i32>::new()), "{}");
+    }
+
+    #[test]
+    fn test_eq() {
+        let mut a = Map::new();
+        let mut b = Map::new();
+        for i in 0..100 {
+            a.insert(i, i * 2);
+            b.insert(99 - i, (99 - i) * 2);
+        }
+        assert_eq!(a, b);
+
+        b.insert(0, 1);
+        assert_ne!(a, b);
+        b.insert(0, 0);
+        assert_eq!(a, b);
+
+        b.delete(&0);
+        assert_ne!(a, b);
+    }
+
+    #[test]
+    fn test_from_iter() {
+        let pairs = vec![
+            ("a".to_string(), 1),
+            ("b".to_string(), 2),
+            ("a".to_string(), 3),
+        ];
+        let map: Map<_, _> = pairs.into_iter().collect();
+        assert_eq!(map.len(), 2);
+        assert_eq!(map.get(&"a".to_string()), Some(&3));
+        assert_eq!(map.get(&"b".to_string()), Some(&2));
+
+        let map: Map<_, _> = (0..1000).map(|i| (i, i)).collect();
+        assert_eq!(map.len(), 1000);
+        assert_eq!(map.resize_count(), 0);
+    }
+
+    #[test]
+    fn test_from_array() {
+        let pairs: [(i32, i32); 1000] = std::array::from_fn(|i| (i as i32 % 800, i as i32));
+        let map = Map::from(pairs);
+        // The table is sized for the whole array, duplicates included, and
+        // built without growing.
+        assert_eq!(
+            map.slot_count(),
+            Map::<i32, i32>::group_count_for(1000, LOAD_FACTOR) * GROUP_SIZE
+        );
+        assert_eq!(map.resize_count(), 0);
+        assert_eq!(map.len(), 800);
+        assert_eq!(map.get(&0), Some(&800));
+        assert_eq!(map.get(&799), Some(&799));
+
+        let map: Map<i32, i32> = Map::from([]);
+        assert_eq!(map.slot_count(), INITIAL_GROUP_COUNT * GROUP_SIZE);
+    }
+
+    #[test]
+    fn test_map_macro() {
+        let map = crate::map! {
+            "a" => 1,
+            "b" => 2,
+            "a" => 3,
+        };
+        assert_eq!(map.len(), 2);
+        assert_eq!(map.get("a"), Some(&3));
+        assert_eq!(map.get("b"), Some(&2));
+
+        let map = crate::map! { 1 => "one", 2 => "two" };
+        assert_eq!(map.len(), 2);
+        assert_eq!(map[&2], "two");
+
+        let map: Map<String, i32> = crate::map! {};
+        assert!(map.is_empty());
+    }
+
+    #[test]
+    fn test_append() {
+        let mut map: Map<_, _> = (0..600).map(|i| (i, i)).collect();
+        let mut other: Map<_, _> = (500..1500).map(|i| (i, -i)).collect();
+        for i in 1400..1500 {
+            other.delete(&i);
+        }
+        let resizes = map.resize_count();
+        let (slot_count, keys) = (other.slot_count(), other.keys.as_ptr());
+        map.append(&mut other);
+
+        assert_eq!(map.len(), 1400);
+        assert_eq!(map.get(&500), Some(&-500));
+        assert_eq!(map.get(&1450), None);
+        // The room is reserved up front, so the table grows once rather than
+        // doubling as it fills.
+        assert_eq!(map.resize_count(), resizes + 1);
+
+        // Draining keeps the other table's slot arrays and clears its deleted
+        // slots along with the full ones.
+        assert!(other.is_empty());
+        assert_eq!(
+            (other.slot_count(), other.keys.as_ptr()),
+            (slot_count, keys)
+        );
+        assert_eq!(other.tombstone_ratio(), 0.0);
+        let resizes = other.resize_count();
+        for i in 0..1000 {
+            other.insert(i, i);
+        }
+        assert_eq!(other.resize_count(), resizes);
+    }
+
+    #[test]
+    fn test_extend() {
+        let mut map = Map::new();
+        map.insert(0, 0);
+        map.insert(1, 1);
+
+        map.extend((1..500).map(|i| (i, i * 2)));
+        assert_eq!(map.len(), 500);
+        assert_eq!(map.get(&0), Some(&0));
+        assert_eq!(map.get(&1), Some(&2));
+        assert_eq!(map.get(&499), Some(&998));
+
+        let other: Map<_, _> = (500..600).map(|i| (i, i)).collect();
+        map.extend(other.iter());
+        assert_eq!(map.len(), 600);
+        assert_eq!(map.get(&550), Some(&550));
+    }
+
+    #[test]
+    fn test_index() {
+        let mut map = Map::new();
+        map.insert("a".to_string(), 1);
+        assert_eq!(map[&"a".to_string()], 1);
+    }
+
+    #[test]
+    #[should_panic(expected = "key not present")]
+    fn test_index_missing() {
+        let map: Map<String, i32> = Map::new();
+        let _ = map[&"a".to_string()];
+    }
+
+    #[test]
+    fn test_borrowed_lookup() {
+        let mut map: Map<String, i32> = Map::new();
+        map.insert("key1".to_string(), 1);
+        map.insert("key2".to_string(), 2);
+        assert_eq!(map.get("key1"), Some(&1));
+        assert!(map.contains_key("key2"));
+        assert_eq!(map["key2"], 2);
+        *map.get_mut("key1").unwrap() += 10;
+        assert_eq!(map.delete("key1"), Some(11));
+        assert_eq!(map.get("key1"), None);
+        assert_eq!(map.len(), 1);
+    }
+
+    #[test]
+    fn test_with_hasher() {
+        use std::hash::{BuildHasherDefault, DefaultHasher};
+
+        type Fixed = BuildHasherDefault<DefaultHasher>;
+        let build = || {
+            let mut map: Map<_, _, _> = Map::with_hasher(Fixed::default());
+            for i in 0..1000 {
+                map.insert(i, i * 2);
+            }
+            map
+        };
+        let (a, b) = (build(), build());
+        assert_eq!(a.get(&500), Some(&1000));
+        assert_eq!(a.hash(&7), b.hash(&7));
+        assert!(a.iter().eq(b.iter()));
+    }
+
+    #[test]
+    fn test_hasher_kept_across_expansions() {
+        #[derive(Clone)]
+        struct Seeded(u64);
+
+        impl BuildHasher for Seeded {
+            type Hasher = DefaultHasher;
+
+            fn build_hasher(&self) -> DefaultHasher {
+                let mut hasher = DefaultHasher::new();
+                hasher.write_u64(self.0);
+                hasher
+            }
+        }
+
+        let mut map: Map<_, _, _> = Map::with_hasher(Seeded(42));
+        for i in 0..5000 {
+            map.insert(i, i);
+            // Every key stays reachable, which it would not if a resize had
+            // switched hashers.
+            if i % 250 == 0 {
+                assert!((0..=i).all(|j| map.get(&j) == Some(&j)));
+            }
+        }
+        assert!(map.resize_count() >= 5);
+        assert_eq!(map.hasher().0, 42);
+        assert_eq!(map.hash(&7), map.split_hash(Seeded(42).hash_one(7)));
+        for i in 0..5000 {
+            assert_eq!(map.get(&i), Some(&i));
+        }
+    }
+
+    #[test]
+    fn test_with_capacity() {
+        let mut map: Map<_, _> = Map::with_capacity(10_000);
+        let slot_count = map.slot_count();
+        assert_eq!(slot_count, 16384);
+        assert!(map.capacity() >= 10_000);
+        for i in 0..10_000 {
+            map.insert(i, i);
+        }
+        assert_eq!(map.slot_count(), slot_count);
+        assert_eq!(map.resize_count(), 0);
+        for i in 0..10_000 {
+            assert_eq!(map.get(&i), Some(&i));
+        }
+
+        let map = Map::<i32, i32>::with_capacity(0);
+        assert_eq!(map.slot_count(), INITIAL_GROUP_COUNT * GROUP_SIZE);
+    }
+
+    #[test]
+    fn test_with_capacity_and_hasher() {
+        use std::hash::{BuildHasherDefault, DefaultHasher};
+
+        let build = || {
+            let mut map: Map<_, _, _> =
+                Map::with_capacity_and_hasher(500, BuildHasherDefault::<DefaultHasher>::default());
+            for i in 0..500 {
+                map.insert(i, i);
+            }
+            map
+        };
+        let (a, b) = (build(), build());
+        assert_eq!(a.resize_count(), 0);
+        assert_eq!(a.hasher().hash_one(42), b.hasher().hash_one(42));
+        assert!(a.iter().eq(b.iter()));
+        assert_eq!(a.get(&499), Some(&499));
+    }
+
+    #[test]
+    fn test_update_all() {
+        let mut map: Map<_, _> = (0..1000).map(|i| (i, i)).collect();
+        for i in (0..1000).step_by(2) {
+            map.delete(&i);
+        }
+        let mut visited = 0;
+        map.update_all(|k, v| {
+            visited += 1;
+            *v += k + 1;
+        });
+
+        // Only full slots are visited, and the deleted ones are left as is.
+        assert_eq!(visited, 500);
+        assert_eq!(map.deleted, 500);
+        for i in 0..1000 {
+            let expected = (i % 2 == 1).then_some(2 * i + 1);
+            assert_eq!(map.get(&i).copied(), expected);
+        }
     }
 
     #[test]
-    fn test_many_insertions_and_deletions() {
+    fn test_retain() {
         let mut map = Map::new();
-        for i in 0..1000 {
+        for i in 0..100 {
             map.insert(i, i);
         }
-        for i in 0..1000 {
-            assert_eq!(map.get(&i), Some(&i));
+        let slot_count = map.slot_count();
+        map.retain(|k, _| k % 3 == 0);
+        assert_eq!(map.len(), 34);
+        for i in 0..100 {
+            assert_eq!(map.get(&i), (i % 3 == 0).then_some(&i));
         }
-        for i in (0..1000).step_by(2) {
-            assert_eq!(map.delete(&i), Some(i));
+
+        // Live and deleted slots together stay under the load factor, so the
+        // new entries fit without growing the table.
+        for i in 100..114 {
+            map.insert(i, i);
         }
-        for i in 0..1000 {
-            if i % 2 == 0 {
-                assert_eq!(map.get(&i), None);
-            } else {
-                assert_eq!(map.get(&i), Some(&i));
-            }
+        assert_eq!(map.len(), 48);
+        assert_eq!(map.slot_count(), slot_count);
+        assert_eq!(map.get(&113), Some(&113));
+    }
+
+    #[test]
+    fn test_drain() {
+        let mut map = Map::new();
+        for i in 0..100 {
+            map.insert(i, i * 10);
         }
+        let slot_count = map.slot_count();
+        let mut drained: Vec<_> = map.drain().collect();
+        drained.sort();
+        assert_eq!(drained, (0..100).map(|i| (i, i * 10)).collect::<Vec<_>>());
+        assert!(map.is_empty());
+        assert_eq!(map.get(&5), None);
+
+        map.insert(1, 1);
+        assert_eq!(map.get(&1), Some(&1));
+        assert_eq!(map.slot_count(), slot_count);
     }
 
     #[test]
-    fn test_contains() {
+    fn test_drain_dropped_early() {
         let mut map = Map::new();
-        map.insert("a".to_string(), 1);
-        assert!(map.contains(&"a".to_string()));
-        assert!(!map.contains(&"b".to_string()));
+        for i in 0..100 {
+            map.insert(i.to_string(), i);
+        }
+        assert_eq!(map.drain().take(3).count(), 3);
+        assert!(map.is_empty());
+        assert!((0..map.slot_count()).all(|i| !is_full(&map.ctrl, i)));
+        assert_eq!(map.iter().count(), 0);
     }
 
     #[test]
-    fn test_iter() {
+    fn test_drop_counts() {
+        let drops = Cell::new(0);
+        let counter = |id| DropCounter { id, drops: &drops };
+
         let mut map = Map::new();
-        map.insert("a".to_string(), 1);
-        map.insert("b".to_string(), 2);
-        let mut std_map = StdHashMap::new();
-        std_map.insert("a".to_string(), 1);
-        std_map.insert("b".to_string(), 2);
+        for i in 0..1000 {
+            map.insert(counter(i), counter(i));
+        }
+        // Growing moves the entries without dropping any.
+        assert_eq!(drops.get(), 0);
 
-        let mut count = 0;
-        for (k, v) in map.iter() {
-            assert_eq!(std_map.get(k), Some(v));
-            count += 1;
+        // The new key and the old value go.
+        assert!(map.insert(counter(0), counter(-1)).is_some());
+        assert_eq!(drops.get(), 2);
+        assert_eq!(map.get(&counter(0)).map(|v| v.id), Some(-1));
+        assert_eq!(drops.get(), 3);
+
+        for i in 0..100 {
+            assert!(map.delete(&counter(i)).is_some());
         }
-        assert_eq!(count, 2);
+        // Each delete drops the probe key, the stored key and the value.
+        assert_eq!(drops.get(), 303);
+        assert!(map.get(&counter(50)).is_none());
+        assert_eq!(map.get(&counter(500)).map(|v| v.id), Some(500));
+        drops.set(0);
+
+        map.retain(|k, _| k.id % 2 == 0);
+        assert_eq!(drops.get(), 900);
+        assert_eq!(map.len(), 450);
+
+        drop(map);
+        assert_eq!(drops.get(), 1800);
     }
 
     #[test]
-    fn test_iter_mut() {
-        let mut map = Map::new();
-        map.insert("a".to_string(), 1);
-        map.insert("b".to_string(), 2);
+    fn test_drop_counts_iterators() {
+        let drops = Cell::new(0);
+        let build = || {
+            let mut map = Map::new();
+            for i in 0..100 {
+                map.insert(
+                    DropCounter {
+                        id: i,
+                        drops: &drops,
+                    },
+                    i,
+                );
+            }
+            map
+        };
 
-        for (_, v) in map.iter_mut() {
-            *v *= 2;
+        let mut into_iter = build().into_iter();
+        drop(into_iter.by_ref().take(10).collect::<Vec<_>>());
+        assert_eq!(drops.get(), 10);
+        drop(into_iter);
+        assert_eq!(drops.get(), 100);
+
+        let mut map = build();
+        drop(map.drain().take(10).collect::<Vec<_>>());
+        assert_eq!(drops.get(), 200);
+        map.insert(
+            DropCounter {
+                id: 0,
+                drops: &drops,
+            },
+            0,
+        );
+        drop(map);
+        assert_eq!(drops.get(), 201);
+
+        // A leaked drain leaks its entries but leaves the map empty and sound.
+        let mut map = build();
+        mem::forget(map.drain());
+        assert!(map.is_empty());
+        drop(map);
+        assert_eq!(drops.get(), 201);
+    }
+
+    #[test]
+    fn test_load_factor() {
+        let mut map = Map::new();
+        assert_eq!(map.load_factor(), 0.0);
+        for i in 0..map.capacity() {
+            map.insert(i, i);
         }
+        assert_eq!(map.resize_count(), 0);
+        assert!(map.load_factor() > 0.85 && map.load_factor() < 0.9);
+        assert_eq!(map.tombstone_ratio(), 0.0);
 
-        assert_eq!(map.get(&"a".to_string()), Some(&2));
-        assert_eq!(map.get(&"b".to_string()), Some(&4));
+        let slot_count = map.slot_count() as f64;
+        for i in 0..10 {
+            map.delete(&i);
+        }
+        assert_eq!(map.load_factor(), (map.capacity() - 10) as f64 / slot_count);
+        assert_eq!(map.tombstone_ratio(), 10.0 / slot_count);
+        map.shrink_to_fit();
+        assert_eq!(map.tombstone_ratio(), 0.0);
     }
 
     #[test]
-    fn test_into_iter() {
+    fn test_capacity_and_reserve() {
         let mut map = Map::new();
-        map.insert("a".to_string(), 1);
-        map.insert("b".to_string(), 2);
-        let mut std_map = StdHashMap::new();
-        std_map.insert("a".to_string(), 1);
-        std_map.insert("b".to_string(), 2);
+        assert_eq!(map.capacity(), 57);
+        map.reserve(10);
+        assert_eq!(map.slot_count(), INITIAL_GROUP_COUNT * GROUP_SIZE);
 
-        let mut count = 0;
-        for (k, v) in map.into_iter() {
-            assert_eq!(std_map.get(&k), Some(&v));
-            count += 1;
+        for i in 0..10 {
+            map.insert(i, i);
         }
-        assert_eq!(count, 2);
+        map.reserve(5000);
+        let slot_count = map.slot_count();
+        assert!(map.capacity() >= 5010);
+        assert_eq!(map.resize_count(), 1);
+
+        // Inserting what was reserved must not grow the table again.
+        for i in 10..5010 {
+            map.insert(i, i);
+        }
+        assert_eq!(map.slot_count(), slot_count);
+        assert_eq!(map.resize_count(), 1);
+        for i in 0..5010 {
+            assert_eq!(map.get(&i), Some(&i));
+        }
+
+        // Tombstones left by deletes count towards the load, so reserve rehashes
+        // in place to clear them.
+        for i in 0..4000 {
+            map.delete(&i);
+        }
+        map.reserve(4000);
+        assert_eq!(map.slot_count(), slot_count);
+        assert_eq!(map.deleted, 0);
+        let resizes = map.resize_count();
+        for i in 0..4000 {
+            map.insert(i, i);
+        }
+        assert_eq!(map.resize_count(), resizes);
     }
 
     #[test]
-    fn test_collision_stress() {
+    fn test_reserve_with_adaptive_load_factor() {
         let mut map = Map::new();
-        let n = if cfg!(miri) { 100 } else { 10000 };
+        map.set_adaptive_load_factor(true);
+        map.reserve(100_000);
+        assert!(map.capacity() >= 100_000);
+        let resizes = map.resize_count();
+        for i in 0..100_000 {
+            map.insert(i, i);
+        }
+        assert_eq!(map.resize_count(), resizes);
 
-        for i in 0..n {
+        for i in 55_000..100_000 {
+            map.delete(&i);
+        }
+        map.shrink_to_fit();
+        assert_eq!(map.ideal_capacity(), map.slot_count());
+        assert!(map.capacity() >= map.len());
+        let resizes = map.resize_count();
+        map.insert(-1, -1);
+        assert_eq!(map.resize_count(), resizes);
+    }
+
+    #[test]
+    fn test_memory_footprint() {
+        let mut map: Map<u64, u64> = Map::new();
+        let initial = map.memory_footprint();
+        assert_eq!(
+            map.memory_footprint(),
+            map.slot_count() * 3 * mem::size_of::<u64>() + map.slot_count()
+        );
+
+        for i in 0..2000 {
             map.insert(i, i);
         }
+        let grown = map.memory_footprint();
+        assert!(grown > initial);
 
-        for i in 0..n {
+        for i in 100..2000 {
+            map.delete(&i);
+        }
+        assert_eq!(map.memory_footprint(), grown);
+        map.shrink_to_fit();
+        assert!(map.memory_footprint() < grown);
+    }
+
+    #[test]
+    fn test_shrink_to_fit() {
+        let mut map = Map::new();
+        for i in 0..2000 {
+            map.insert(i, i);
+        }
+        for i in 100..2000 {
+            map.delete(&i);
+        }
+        let slot_count = map.slot_count();
+
+        map.shrink_to_fit();
+        assert!(map.slot_count() < slot_count);
+        assert_eq!(map.slot_count(), INITIAL_GROUP_COUNT * GROUP_SIZE * 2);
+        assert_eq!(map.deleted, 0);
+        assert_eq!(map.len(), 100);
+        for i in 0..100 {
             assert_eq!(map.get(&i), Some(&i));
         }
+        assert_eq!(map.get(&100), None);
+    }
 
-        for i in (0..n).step_by(2) {
-            assert_eq!(map.delete(&i), Some(i));
+    #[test]
+    fn test_clear() {
+        let mut map = Map::new();
+        for i in 0..1000 {
+            map.insert(i, i);
         }
+        for i in 0..300 {
+            map.delete(&i);
+        }
+        let (slot_count, resizes) = (map.slot_count(), map.resize_count());
+        let (keys, ctrl) = (map.keys.as_ptr(), map.ctrl.as_ptr());
 
-        for i in 0..n {
-            if i % 2 == 0 {
-                assert_eq!(map.get(&i), None);
-            } else {
-                assert_eq!(map.get(&i), Some(&i));
-            }
+        map.clear();
+        assert_eq!(map.len(), 0);
+        assert_eq!(map.iter().count(), 0);
+        assert_eq!(map.get(&500), None);
+        assert_eq!(map.deleted, 0);
+        let empty = Ctrl::<GROUP_SIZE>::SLOT_EMPTY;
+        assert!(map.ctrl.iter().all(|group| group.0 == [empty; GROUP_SIZE]));
+
+        for i in 0..1000 {
+            map.insert(i, -i);
         }
+        assert_eq!(map.slot_count(), slot_count);
+        assert_eq!(map.resize_count(), resizes);
+        assert_eq!((map.keys.as_ptr(), map.ctrl.as_ptr()), (keys, ctrl));
+        assert_eq!(map.get(&999), Some(&-999));
 
-        for i in (0..n).step_by(2) {
-            map.insert(i, i * 2);
+        let drops = Cell::new(0);
+        let mut map = Map::new();
+        for i in 0..100 {
+            map.insert(
+                i,
+                DropCounter {
+                    id: i,
+                    drops: &drops,
+                },
+            );
         }
+        map.clear();
+        assert_eq!(drops.get(), 100);
+        drop(map);
+        assert_eq!(drops.get(), 100);
+    }
 
-        for i in 0..n {
-            if i % 2 == 0 {
-                assert_eq!(map.get(&i), Some(&(i * 2)));
-            } else {
-                assert_eq!(map.get(&i), Some(&i));
+    #[test]
+    fn test_clear_with_panicking_drop() {
+        struct PanicOnDrop(i32);
+
+        impl Drop for PanicOnDrop {
+            fn drop(&mut self) {
+                if self.0 == 50 {
+                    panic!("drop failed");
+                }
             }
         }
+
+        let mut map = Map::new();
+        for i in 0..100 {
+            map.insert(i, PanicOnDrop(i));
+        }
+        let result = panic::catch_unwind(panic::AssertUnwindSafe(|| map.clear()));
+        assert!(result.is_err());
+        assert_eq!(map.len(), 0);
+        assert_eq!(map.iter().len(), 0);
+        assert!(map.get(&99).is_none());
+
+        map.insert(1, PanicOnDrop(1));
+        assert_eq!(map.len(), 1);
     }
 
     #[test]
-    fn test_high_load_delete_reinsert() {
+    fn test_get_key_value() {
         let mut map = Map::new();
-        let n = 1000;
+        let key = "one".to_string();
+        let stored = key.as_ptr();
+        map.insert(key, 1);
+        let lookup = "one".to_string();
+        let (k, v) = map.get_key_value(&lookup).unwrap();
+        assert_eq!((k.as_str(), *v), ("one", 1));
+        assert_eq!(k.as_ptr(), stored);
+        assert_ne!(k.as_ptr(), lookup.as_ptr());
+        assert_eq!(map.get_key_value("two"), None);
+    }
 
-        // Fill the map to a high load factor
-        for i in 0..n {
+    #[test]
+    fn test_remove_entry() {
+        let mut map = Map::new();
+        map.insert("one".to_string(), 1);
+        map.insert("two".to_string(), 2);
+        assert_eq!(map.remove_entry("one"), Some(("one".to_string(), 1)));
+        assert_eq!(map.count, 1);
+        assert_eq!(map.remove_entry("one"), None);
+        assert_eq!(map.get("two"), Some(&2));
+    }
+
+    #[test]
+    fn test_churn_reclaims_deleted_slots() {
+        let mut map = Map::new();
+        for i in 0..20 {
             map.insert(i, i);
         }
-
-        // Delete a large portion of the items
-        for i in (0..n).step_by(2) {
+        let slot_count = map.slot_count();
+        for i in 20..50_000 {
+            map.insert(i, i);
             assert_eq!(map.delete(&i), Some(i));
         }
+        assert_eq!(map.slot_count(), slot_count);
+        assert_eq!(map.len(), 20);
+        for i in 0..20 {
+            assert_eq!(map.get(&i), Some(&i));
+        }
+        assert_eq!(map.get(&49_999), None);
+        let deleted = map
+            .ctrl
+            .iter()
+            .flat_map(|ctrl| (0..GROUP_SIZE).map(|i| ctrl.get(i)))
+            .filter(|&c| c == Ctrl::<GROUP_SIZE>::SLOT_DELETED)
+            .count();
+        assert_eq!(map.deleted, deleted);
+        assert!(!map.is_overloaded());
+    }
 
-        // Check that the remaining items are still there
-        for i in 0..n {
-            if i % 2 == 0 {
-                assert_eq!(map.get(&i), None);
-            } else {
-                assert_eq!(map.get(&i), Some(&i));
+    #[test]
+    fn test_hash_mask_matches_modulo() {
+        let mut map = Map::new();
+        for i in 0..1000 {
+            let h1 = map.hasher.hash_one(i) >> 7;
+            assert_eq!(map.hash(&i).0, (h1 % map.group_count as u64) as usize);
+            map.insert(i, i);
+        }
+        assert!(map.group_count > INITIAL_GROUP_COUNT);
+    }
+
+    #[test]
+    fn test_get_disjoint_mut() {
+        let mut map = Map::new();
+        map.insert("a".to_string(), 1);
+        map.insert("b".to_string(), 2);
+        let [a, b, c] = map.get_disjoint_mut(["a", "b", "c"]);
+        assert!(c.is_none());
+        let (a, b) = (a.unwrap(), b.unwrap());
+        mem::swap(a, b);
+        *a *= 10;
+        assert_eq!(map.get("a"), Some(&20));
+        assert_eq!(map.get("b"), Some(&1));
+    }
+
+    #[test]
+    #[should_panic(expected = "duplicate keys")]
+    fn test_get_disjoint_mut_duplicate() {
+        let mut map = Map::new();
+        map.insert(1, 1);
+        let _ = map.get_disjoint_mut([&1, &1]);
+    }
+
+    #[test]
+    fn test_resize_does_not_rehash() {
+        use crate::fixtures::HashCounter;
+
+        let hashes = Cell::new(0);
+        let key = |id| HashCounter {
+            id,
+            hashes: &hashes,
+        };
+        let mut map = Map::new();
+        for id in 0..1000 {
+            map.insert(key(id), id);
+        }
+        assert!(map.group_count > INITIAL_GROUP_COUNT);
+        assert_eq!(hashes.get(), 1000);
+
+        // Shrinking and incremental growth move entries by their stored hashes
+        // as well; only the lookups below hash anything.
+        for id in 100..1000 {
+            map.delete(&key(id));
+        }
+        let resizes = map.resize_count();
+        map.shrink_to_fit();
+        map.rehash_incremental(10, |_, _| {});
+        assert_eq!(map.resize_count(), resizes + 2);
+        assert_eq!(hashes.get(), 1900);
+        assert_eq!(map.get(&key(7)), Some(&7));
+        assert_eq!(hashes.get(), 1901);
+    }
+
+    #[test]
+    fn test_find_h2_mask() {
+        let mut ctrl = Ctrl::<16>::new();
+        let tags = [3, 9, 3, 3, 0, 9, 3, 1, 9, 2, 3, 4, 5, 6, 7, 3];
+        for (i, h2) in tags.into_iter().enumerate() {
+            ctrl.set(i, Slot::Occupied(h2));
+        }
+        assert_eq!(ctrl.find_h2(3), (0b1000_0100_0100_1101, false));
+        assert_eq!(ctrl.find_h2(9), (0b0000_0001_0010_0010, false));
+        assert_eq!(ctrl.find_h2(0x7f), (0, false));
+
+        let mut ctrl = Ctrl::<8>::new();
+        for (i, h2) in [3, 9, 3, 3, 0, 9, 3, 1].into_iter().enumerate() {
+            ctrl.set(i, Slot::Occupied(h2));
+        }
+        assert_eq!(ctrl.find_h2(3), (0b0100_1101, false));
+        assert_eq!(ctrl.find_empty_and_deleted(), None);
+
+        let mut ctrl = Ctrl::<16>::new();
+        ctrl.set(0, Slot::Occupied(5));
+        ctrl.set(1, Slot::Deleted);
+        ctrl.set(2, Slot::Occupied(5));
+        let (matches, found_empty) = ctrl.find_h2(5);
+        assert_eq!(matches, 0b101);
+        assert!(found_empty);
+    }
+
+    fn check_ctrl_matches_naive<const G: usize>() {
+        let naive = |ctrl: Ctrl<G>, byte: u8| {
+            (0..G)
+                .filter(|&i| ctrl.get(i) == byte)
+                .fold(0u16, |mask, i| mask | 1 << i)
+        };
+        let lanes = [
+            Ctrl::<G>::SLOT_EMPTY,
+            Ctrl::<G>::SLOT_DELETED,
+            0,
+            1,
+            0x7f,
+            0x40,
+        ];
+        let mut state = 0x9e37_79b9_7f4a_7c15_u64;
+        for _ in 0..200 {
+            let mut ctrl = Ctrl::<G>::new();
+            for i in 0..G {
+                // xorshift64, with lanes picked mostly from tags and edge values
+                state ^= state << 13;
+                state ^= state >> 7;
+                state ^= state << 17;
+                let r = state as u8;
+                ctrl.0[i] = if r & 0x80 == 0 {
+                    r & 0x7f
+                } else {
+                    lanes[r as usize % lanes.len()]
+                };
+            }
+            for h2 in 0..=255 {
+                assert_eq!(ctrl.match_byte_swar(h2), naive(ctrl, h2));
+                assert_eq!(ctrl.match_byte(h2), naive(ctrl, h2));
             }
+            let free = naive(ctrl, Ctrl::<G>::SLOT_EMPTY) | naive(ctrl, Ctrl::<G>::SLOT_DELETED);
+            assert_eq!(ctrl.match_empty_or_deleted_swar(), free);
+            assert_eq!(ctrl.match_empty_or_deleted(), free);
         }
+    }
 
-        // Re-insert all items, some will be new, some will overwrite tombstones
-        for i in 0..n {
-            map.insert(i, i * 10);
+    #[test]
+    fn test_ctrl_matches_naive() {
+        check_ctrl_matches_naive::<8>();
+        check_ctrl_matches_naive::<16>();
+    }
+
+    fn check_group_width<const G: usize>() {
+        let mut map: Map<u64, u64, RandomState, G> = Map::default();
+        let mut std_map = StdHashMap::new();
+        for i in 0..2000 {
+            assert_eq!(map.insert(i, i), std_map.insert(i, i));
+        }
+        assert!(map.slot_count() > 2000);
+        for i in (0..2000).step_by(3) {
+            assert_eq!(map.insert(i, i * 2), std_map.insert(i, i * 2));
+        }
+        for i in (0..2000).step_by(2) {
+            assert_eq!(map.delete(&i), std_map.remove(&i));
         }
+        // Churn through the deleted slots left behind.
+        for i in 2000..6000 {
+            map.insert(i, i);
+            map.delete(&(i - 1000));
+            std_map.insert(i, i);
+            std_map.remove(&(i - 1000));
+        }
+        map.retain(|k, _| k % 5 != 0);
+        std_map.retain(|k, _| k % 5 != 0);
 
-        // Check that all items are present with their new values
-        for i in 0..n {
-            assert_eq!(map.get(&i), Some(&(i * 10)));
+        assert_eq!(map.len(), std_map.len());
+        for i in 0..6000 {
+            assert_eq!(map.get(&i), std_map.get(&i));
+        }
+        let mut entries: Vec<_> = map.iter().map(|(&k, &v)| (k, v)).collect();
+        let mut expected: Vec<_> = std_map.into_iter().collect();
+        entries.sort();
+        expected.sort();
+        assert_eq!(entries, expected);
+
+        let collected: Map<u64, u64, RandomState, G> = map.drain().collect();
+        assert!(map.is_empty());
+        let mut entries: Vec<_> = collected.into_iter().collect();
+        entries.sort();
+        assert_eq!(entries, expected);
+
+        let mut sized: Map<u64, u64, RandomState, G> = Map::with_capacity(5000);
+        for i in 0..5000 {
+            sized.insert(i, i);
         }
+        assert_eq!(sized.resize_count(), 0);
+
+        let mut builder: MapBuilder<u64, u64, G> = MapBuilder::with_chunk_size(100);
+        for i in 0..1000 {
+            builder.push(i, i);
+        }
+        assert_eq!(builder.finish().len(), 1000);
+
+        let mut set: Set<u64, RandomState, G> = Set::with_hasher(RandomState::new());
+        set.extend(0..100);
+        assert_eq!(set.len(), 100);
+        assert!(set.contains(&99));
+    }
+
+    #[test]
+    fn test_group_width_8() {
+        check_group_width::<8>();
+    }
+
+    #[test]
+    fn test_group_width_16() {
+        check_group_width::<16>();
+    }
+
+    #[cfg(feature = "rayon")]
+    #[test]
+    fn test_par_iter() {
+        use rayon::prelude::*;
+
+        let mut map: Map<u64, u64> = (0..100_000).map(|i| (i, i)).collect();
+        for i in (0..100_000).step_by(3) {
+            map.delete(&i);
+        }
+        let serial: u64 = map.values().sum();
+        assert_eq!(map.par_iter().map(|(_, v)| v).sum::<u64>(), serial);
+        assert_eq!(map.par_iter().count(), map.len());
+
+        map.par_iter_mut().for_each(|(k, v)| *v += k);
+        assert_eq!(map.values().sum::<u64>(), 2 * serial);
+        assert_eq!(map.get(&1), Some(&2));
+
+        let narrow: Map<u64, u64, RandomState, 8> = (0..1000).map(|i| (i, i)).collect();
+        assert_eq!(narrow.par_iter().map(|(_, v)| v).sum::<u64>(), 499_500);
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn test_serde_group_width() {
+        let map: Map<String, i32, RandomState, 8> =
+            (0..100).map(|i| (format!("key{}", i), i)).collect();
+        let json = serde_json::to_string(&map).unwrap();
+        let back: Map<String, i32, RandomState, 8> = serde_json::from_str(&json).unwrap();
+        assert_eq!(back, map);
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn test_serde_caps_preallocation() {
+        use serde::{
+            Deserialize,
+            de::value::{Error, MapDeserializer},
+        };
+
+        /// Yields a few entries while claiming to hold far more.
+        struct Lying(std::ops::Range<u64>);
+
+        impl Iterator for Lying {
+            type Item = (u64, u64);
+
+            fn next(&mut self) -> Option<(u64, u64)> {
+                self.0.next().map(|i| (i, i))
+            }
+
+            fn size_hint(&self) -> (usize, Option<usize>) {
+                (1 << 40, Some(1 << 40))
+            }
+        }
+
+        let deserializer = MapDeserializer::<_, Error>::new(Lying(0..3));
+        let map = Map::<u64, u64>::deserialize(deserializer).unwrap();
+        assert_eq!(map.len(), 3);
+        assert_eq!(
+            map.slot_count(),
+            Map::<u64, u64>::group_count_for(crate::MAX_PREALLOCATED, LOAD_FACTOR) * GROUP_SIZE
+        );
     }
 }